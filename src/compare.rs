@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{Error, Result};
+
+use crate::report::Report;
+
+// Default tolerance band in percent, a change in the bad direction within
+// the band isn't considered as a regression.
+const DEFAULT_TOLERANCE: f64 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Better {
+    Higher,
+    Lower,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Unchanged,
+    Improved,
+    Regressed,
+    // the baseline has no value to compare against
+    NotComparable,
+}
+
+#[derive(Debug, Clone)]
+pub struct Delta {
+    pub metric: &'static str,
+    pub baseline: f64,
+    pub candidate: f64,
+    pub tolerance: f64,
+    pub status: Status,
+}
+
+impl Delta {
+    // change in percent of the baseline, None when the baseline is zero
+    pub fn change(&self) -> Option<f64> {
+        if self.baseline == 0.0 {
+            return None;
+        }
+        Some((self.candidate - self.baseline) * 100.0 / self.baseline.abs())
+    }
+}
+
+// Tolerances holds the tolerance band of every metric, in percent
+#[derive(Debug, Clone)]
+pub struct Tolerances {
+    default: f64,
+    metrics: HashMap<String, f64>,
+}
+
+impl Default for Tolerances {
+    fn default() -> Self {
+        Tolerances {
+            default: DEFAULT_TOLERANCE,
+            metrics: HashMap::new(),
+        }
+    }
+}
+
+impl Tolerances {
+    // parse accepts `PCT` for the default band and `METRIC=PCT` for the band
+    // of a single metric.
+    pub fn parse(values: &[String]) -> Result<Tolerances> {
+        let mut tolerances = Tolerances::default();
+        for value in values {
+            match value.split_once('=') {
+                Some((metric, pct)) => {
+                    if !METRICS.iter().any(|(name, _, _)| *name == metric) {
+                        return Err(Error::other(format!("unknown metric {}", metric)));
+                    }
+                    tolerances
+                        .metrics
+                        .insert(metric.to_string(), parse_pct(pct)?);
+                }
+                None => tolerances.default = parse_pct(value)?,
+            }
+        }
+        Ok(tolerances)
+    }
+
+    fn get(&self, metric: &str) -> f64 {
+        *self.metrics.get(metric).unwrap_or(&self.default)
+    }
+}
+
+fn parse_pct(value: &str) -> Result<f64> {
+    match value.trim_end_matches('%').parse::<f64>() {
+        Ok(pct) if pct >= 0.0 => Ok(pct),
+        _ => Err(Error::other(format!("invalid tolerance {}", value))),
+    }
+}
+
+type Extractor = fn(&Report) -> f64;
+
+const METRICS: [(&str, Better, Extractor); 13] = [
+    ("throughput", Better::Higher, |r| r.publish.throughput),
+    ("achievedRate", Better::Higher, |r| r.publish.achieved_rate),
+    ("ackLatencyP50", Better::Lower, |r| r.ack_latency.p50),
+    ("ackLatencyP90", Better::Lower, |r| r.ack_latency.p90),
+    ("ackLatencyP99", Better::Lower, |r| r.ack_latency.p99),
+    ("ackLatencyMax", Better::Lower, |r| r.ack_latency.max),
+    ("connectSuccessRate", Better::Higher, |r| {
+        r.connections.success_rate
+    }),
+    ("acceptedConnections", Better::Higher, |r| {
+        r.connections.accepted as f64
+    }),
+    ("failedTasks", Better::Lower, |r| {
+        r.errors.failed_tasks as f64
+    }),
+    ("errorRate", Better::Lower, |r| {
+        if r.connections.planned == 0 {
+            0.0
+        } else {
+            r.errors.failed_tasks as f64 * 100.0 / r.connections.planned as f64
+        }
+    }),
+    ("invalidPubacks", Better::Lower, |r| {
+        r.errors.invalid_pubacks as f64
+    }),
+    ("notReady", Better::Lower, |r| {
+        r.errors.timeout_pubacks as f64
+    }),
+    ("sentPackets", Better::Higher, |r| r.publish.sent as f64),
+];
+
+pub fn compare(baseline: &Report, candidate: &Report, tolerances: &Tolerances) -> Vec<Delta> {
+    METRICS
+        .iter()
+        .map(|(metric, better, extract)| {
+            let tolerance = tolerances.get(metric);
+            let (baseline, candidate) = (extract(baseline), extract(candidate));
+            let mut delta = Delta {
+                metric,
+                baseline,
+                candidate,
+                tolerance,
+                status: Status::Unchanged,
+            };
+            let worse = match better {
+                Better::Higher => candidate < baseline,
+                Better::Lower => candidate > baseline,
+            };
+            let change = match delta.change() {
+                Some(change) => change,
+                None => {
+                    if candidate != 0.0 {
+                        delta.status = Status::NotComparable;
+                    }
+                    return delta;
+                }
+            };
+            if change.abs() > tolerance {
+                delta.status = if worse {
+                    Status::Regressed
+                } else {
+                    Status::Improved
+                };
+            }
+            delta
+        })
+        .collect()
+}
+
+// markdown renders the deltas as a table suitable for a pull-request comment
+pub fn markdown(baseline: &Report, candidate: &Report, deltas: &[Delta]) -> String {
+    let mut out = String::new();
+    let regressions = deltas
+        .iter()
+        .filter(|d| d.status == Status::Regressed)
+        .count();
+    let _ = writeln!(
+        out,
+        "### mqtt-bench: `{}` vs `{}`\n",
+        baseline.task_name, candidate.task_name
+    );
+    for (name, report) in [("baseline", baseline), ("candidate", candidate)] {
+        if !report.labels.is_empty() {
+            let _ = writeln!(out, "- {}: {}", name, report.format_labels());
+        }
+    }
+    if regressions == 0 {
+        let _ = writeln!(out, "No regressions found.\n");
+    } else {
+        let _ = writeln!(out, "**{} regression(s) found.**\n", regressions);
+    }
+    let _ = writeln!(
+        out,
+        "| metric | baseline | candidate | delta | tolerance | status |"
+    );
+    let _ = writeln!(out, "|---|---:|---:|---:|---:|---|");
+    for delta in deltas {
+        let change = match delta.change() {
+            Some(change) => format!("{:+.2}%", change),
+            None if delta.candidate == 0.0 => "0.00%".to_string(),
+            None => "n/a".to_string(),
+        };
+        let status = match delta.status {
+            Status::Unchanged => "ok",
+            Status::Improved => "improved",
+            Status::Regressed => "**regressed**",
+            Status::NotComparable => "n/a",
+        };
+        let _ = writeln!(
+            out,
+            "| {} | {:.2} | {:.2} | {} | ±{}% | {} |",
+            delta.metric, delta.baseline, delta.candidate, change, delta.tolerance, status
+        );
+    }
+    out
+}
+
+pub fn command() -> clap::Command {
+    clap::Command::new("compare")
+        .about("Compare two JSON run reports and flag regressions")
+        .arg(
+            clap::arg!(<BASELINE> "Report of the baseline run")
+                .value_parser(clap::value_parser!(std::path::PathBuf)),
+        )
+        .arg(
+            clap::arg!(<CANDIDATE> "Report of the candidate run")
+                .value_parser(clap::value_parser!(std::path::PathBuf)),
+        )
+        .arg(
+            clap::arg!(--"tolerance" <TOLERANCE>)
+                .short(Some('t'))
+                .action(clap::ArgAction::Append)
+                .help("Tolerance band in percent, `PCT` for all metrics or `METRIC=PCT`"),
+        )
+}
+
+// execute prints the comparison, the exit code is non-zero when any metric
+// regressed.
+pub fn execute(matches: &clap::ArgMatches) -> i32 {
+    let load = |name: &str| {
+        let path = matches.get_one::<std::path::PathBuf>(name).unwrap();
+        Report::from_file(path)
+            .map_err(|e| Error::other(format!("read report {} failed: {}", path.display(), e)))
+    };
+    let (baseline, candidate) = match (load("BASELINE"), load("CANDIDATE")) {
+        (Ok(baseline), Ok(candidate)) => (baseline, candidate),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    let values: Vec<String> = matches
+        .get_many::<String>("tolerance")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    let tolerances = match Tolerances::parse(&values) {
+        Ok(tolerances) => tolerances,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    let deltas = compare(&baseline, &candidate, &tolerances);
+    print!("{}", markdown(&baseline, &candidate, &deltas));
+    if deltas.iter().any(|d| d.status == Status::Regressed) {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{compare, markdown, Status, Tolerances};
+    use crate::report::Report;
+    use crate::stressing_registry::MetricRegistry;
+
+    fn report(acked: usize, latency: u64) -> Report {
+        let reg = MetricRegistry::new("task-demo".to_string());
+        reg.start_task();
+        reg.planned_tasks_add(1);
+        reg.accepted_connections_inc();
+        reg.target_publishes_add(100);
+        for _ in 0..acked {
+            reg.publish_packets_inc();
+            reg.ack_latency_observe(Duration::from_millis(latency));
+        }
+        reg.task_stopped();
+        Report::from_snapshot(&reg.snapshot())
+    }
+
+    #[test]
+    fn test_compare_reports() {
+        let baseline = report(100, 10);
+        let candidate = report(97, 20);
+
+        let tolerances = Tolerances::parse(&["ackLatencyP99=200".to_string()]).unwrap();
+        let deltas = compare(&baseline, &candidate, &tolerances);
+        let status = |metric: &str| deltas.iter().find(|d| d.metric == metric).unwrap().status;
+        // 3% drop is within the default band
+        assert_eq!(status("achievedRate"), Status::Unchanged);
+        assert_eq!(status("ackLatencyP50"), Status::Regressed);
+        assert_eq!(status("ackLatencyP99"), Status::Unchanged);
+        assert_eq!(status("connectSuccessRate"), Status::Unchanged);
+
+        let table = markdown(&baseline, &candidate, &deltas);
+        assert!(
+            table.contains("| ackLatencyP50 | 10.01 | 20.02 | +100.01% | ±5% | **regressed** |")
+        );
+    }
+
+    #[test]
+    fn test_compare_zero_baseline() {
+        // no publish acknowledged by the baseline, its latencies are zero
+        let baseline = report(0, 10);
+        let candidate = report(100, 10);
+
+        let deltas = compare(&baseline, &candidate, &Tolerances::default());
+        let status = |metric: &str| deltas.iter().find(|d| d.metric == metric).unwrap().status;
+        assert_eq!(status("ackLatencyP50"), Status::NotComparable);
+        assert_eq!(status("ackLatencyMax"), Status::NotComparable);
+        assert_eq!(status("failedTasks"), Status::Unchanged);
+
+        let table = markdown(&baseline, &candidate, &deltas);
+        assert!(table.contains("No regressions found."));
+        assert!(table.contains("| ackLatencyP50 | 0.00 | 10.01 | n/a | ±5% | n/a |"));
+    }
+
+    #[test]
+    fn test_parse_tolerances() {
+        assert!(Tolerances::parse(&["10%".to_string()]).is_ok());
+        assert!(Tolerances::parse(&["unknown=10".to_string()]).is_err());
+        assert!(Tolerances::parse(&["-1".to_string()]).is_err());
+    }
+}
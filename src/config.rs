@@ -1,14 +1,25 @@
+use base64::{engine::general_purpose, Engine as _};
 use rand::{distributions::Alphanumeric, Rng};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_yaml;
 use std::{
-    collections::HashMap,
-    fs,
-    io::{Error, ErrorKind, Result},
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{Error, Result},
+    ops::Range,
+    path::Path,
     sync::Arc,
+    time::Duration,
 };
 
-use crate::util::{http_rpc_call, render_template, MyClient};
+use crate::error::BenchResult;
+use crate::inventory::DeviceReader;
+use crate::migrate;
+use crate::overrides::{self, Override};
+use crate::preprocess::preprocess;
+use crate::stressing_registry;
+use crate::util::{http_rpc_call, render_template, MyClient, TokenRequest};
 
 const DEFAULT_AUTHENTICATION_PAYLOAD: &str = r#"
 {
@@ -25,22 +36,62 @@ const DEFAULT_AUTHENTICATION_PAYLOAD: &str = r#"
 
 const DEFAULT_TOKEN_EXTRACTOR: &str = ".data.token";
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct MetaData {
+    /// Name of the spec, the `task_name` label of its metrics
     pub name: String,
+    /// Labels attached to the exported metrics and to the report
     #[serde(default = "default_meta_label")]
     pub label: HashMap<String, String>,
 }
-#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone)]
+impl MetaData {
+    // metric_labels returns the labels attached to every exported metric,
+    // labels which aren't valid Prometheus label names are skipped.
+    pub fn metric_labels(&self) -> Vec<(String, String)> {
+        let mut labels: Vec<(String, String)> = self
+            .label
+            .iter()
+            .filter(|(k, _)| {
+                let valid =
+                    is_valid_label_name(k) && !stressing_registry::LABELS.contains(&k.as_str());
+                if !valid {
+                    tracing::warn!(
+                        label = %k,
+                        "label of metaData is ignored, it isn't a valid metric label"
+                    );
+                }
+                valid
+            })
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        labels.sort();
+        labels
+    }
+}
+
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    !name.starts_with("__") && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone, JsonSchema)]
 #[serde(rename_all = "camelCase")]
+#[allow(clippy::upper_case_acronyms)]
 pub struct GVK {
+    /// Always github.com/zhao-kun/mqtt-bench
     group: String,
+    /// Version of the config format, v1.0.1, an older version is upgraded by the migrate subcommand
     version: String,
     meta_data: MetaData,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// A mqtt-bench config document, the `kind` picks the type of the `spec`
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Stressing {
     #[serde(flatten)]
@@ -50,58 +101,347 @@ pub struct Stressing {
     pub spec: Spec,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase", tag = "kind", content = "spec")]
+#[allow(clippy::large_enum_variant)]
 pub enum Spec {
     Test(Value),
     Publish(Config),
+    #[serde(alias = "Plan")]
+    Plan(Plan),
+}
+
+/// Plan lists the specs run by a single process, e.g. publish plus a
+/// connection storm
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Plan {
+    #[serde(default)]
+    pub mode: PlanMode,
+    /// Specs of the plan, their names must be unique
+    pub specs: Vec<Stressing>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum PlanMode {
+    /// All specs start at once
+    #[default]
+    Concurrent,
+    /// A spec starts when the previous one finished
+    Sequential,
+}
+
+impl Plan {
+    // from_documents returns the name and the plan of the documents of a
+    // config file, a `kind: plan` document is unwrapped and several spec
+    // documents run concurrently.
+    pub fn from_documents(mut documents: Vec<Stressing>) -> Result<(String, Plan)> {
+        let plans = documents
+            .iter()
+            .filter(|d| matches!(d.spec, Spec::Plan(_)))
+            .count();
+        if documents.is_empty() {
+            return Err(Error::other("no spec in the config"));
+        }
+        for document in &documents {
+            document.check_version()?;
+        }
+        if plans > 0 && documents.len() > 1 {
+            return Err(Error::other(
+                "a plan must be the only document of the config",
+            ));
+        }
+        if plans == 1 {
+            let document = documents.remove(0);
+            let name = document.meta().name;
+            match document.spec {
+                Spec::Plan(plan) => return Ok((name, plan)),
+                _ => unreachable!(),
+            }
+        }
+        let names: Vec<String> = documents.iter().map(|d| d.meta().name).collect();
+        let plan = Plan {
+            mode: PlanMode::Concurrent,
+            specs: documents,
+        };
+        Ok((names.join("+"), plan))
+    }
+}
+
+impl Stressing {
+    // check_version fails unless the document and the specs of a plan are of
+    // a version `migrate` can upgrade to the current one, it's done when the
+    // config is read
+    fn check_version(&self) -> Result<()> {
+        let version = &self.gvk.version;
+        if !migrate::is_supported(version) {
+            return Err(Error::other(format!(
+                "version {} of {} is unknown, the supported versions are {}",
+                version,
+                self.gvk.meta_data.name,
+                migrate::supported_versions()
+            )));
+        }
+        match &self.spec {
+            Spec::Plan(plan) => plan.specs.iter().try_for_each(Stressing::check_version),
+            _ => Ok(()),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// Spec of the test kind, it can't be run
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Value {
     value: String,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
-#[serde(rename_all = "camelCase")]
+/// DynamicToken requests the MQTT password of every device from an HTTP
+/// endpoint, the static password is used if the url is empty
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct DynamicToken {
+    /// Token endpoint, prefixed by one of the servers
     pub url: String,
+    /// Template of the request body, rendered with the fields and the context of the device
+    #[serde(default = "default_token_payload")]
     pub payload: String,
+    /// JSONPath of the token in the response
     pub token_extractor: String,
+    /// HTTP method of the request
     #[serde(default = "default_method_value")]
     pub method: String,
+    /// Hosts prefixed to the url, picked randomly, the url is used as is if empty
+    #[serde(default)]
     pub servers: Vec<String>,
+    /// Query parameters of the request, the values are templates like the payload
+    #[serde(default)]
+    pub query: HashMap<String, String>,
+    /// Headers of the request, the values are templates like the payload, e.g.
+    /// `X-Api-Key: ${apiKey}` or `Authorization: Bearer ${password}`
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Basic authentication of the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub basic_auth: Option<BasicAuth>,
+    /// Content type of the payload, a GET request has no payload
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    /// Milliseconds to wait for the response, 0 waits forever
+    #[serde(default = "default_token_timeout")]
+    pub timeout: u64,
+    /// Status codes of a successful response
+    #[serde(default = "default_accepted_status")]
+    pub accepted_status: Vec<u16>,
+}
+
+/// Credentials of the basic authentication, the fields are templates like the payload
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuth {
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+}
+
+// Methods of the token request, only POST and PUT send the payload
+pub const TOKEN_METHODS: [&str; 3] = ["GET", "POST", "PUT"];
+
+fn default_token_payload() -> String {
+    DEFAULT_AUTHENTICATION_PAYLOAD.to_string()
 }
 
 fn default_method_value() -> String {
     "POST".to_string()
 }
 
+fn default_content_type() -> String {
+    "application/json".to_string()
+}
+
+// default token timeout is ten seconds
+fn default_token_timeout() -> u64 {
+    10000
+}
+
+fn default_accepted_status() -> Vec<u16> {
+    vec![200]
+}
+
 impl DynamicToken {
     pub fn new() -> DynamicToken {
         DynamicToken {
             url: "".to_string(),
-            payload: DEFAULT_AUTHENTICATION_PAYLOAD.to_string(),
+            payload: default_token_payload(),
             token_extractor: DEFAULT_TOKEN_EXTRACTOR.to_string(),
             method: default_method_value(),
             servers: vec![],
+            query: HashMap::new(),
+            headers: HashMap::new(),
+            basic_auth: None,
+            content_type: default_content_type(),
+            timeout: default_token_timeout(),
+            accepted_status: default_accepted_status(),
         }
     }
+
+    fn has_payload(&self) -> bool {
+        !self.method.eq_ignore_ascii_case("GET")
+    }
+
+    // templates returns the templates of the request by their field, e.g.
+    // `headers.X-Api-Key`, the payload of a GET request isn't sent
+    pub fn templates(&self) -> Vec<(String, &str)> {
+        let mut templates = vec![];
+        if self.has_payload() {
+            templates.push(("payload".to_string(), self.payload.as_str()));
+        }
+        for (field, map) in [("query", &self.query), ("headers", &self.headers)] {
+            let mut fields: Vec<_> = map
+                .iter()
+                .map(|(name, value)| (format!("{}.{}", field, name), value.as_str()))
+                .collect();
+            fields.sort();
+            templates.extend(fields);
+        }
+        if let Some(auth) = &self.basic_auth {
+            templates.push(("basicAuth.username".to_string(), auth.username.as_str()));
+            templates.push(("basicAuth.password".to_string(), auth.password.as_str()));
+        }
+        templates
+    }
+
+    // render returns the request of a device, the context is the fields and
+    // the context of the device
+    pub fn render(&self, context: &HashMap<&str, &str>) -> TokenRequest {
+        let render = |map: &HashMap<String, String>| -> BTreeMap<String, String> {
+            map.iter()
+                .map(|(name, value)| (name.clone(), render_template(value, context)))
+                .collect()
+        };
+        let mut headers = render(&self.headers);
+        if let Some(auth) = &self.basic_auth {
+            let credentials = format!(
+                "{}:{}",
+                render_template(&auth.username, context),
+                render_template(&auth.password, context)
+            );
+            headers.insert(
+                "Authorization".to_string(),
+                format!("Basic {}", general_purpose::STANDARD.encode(credentials)),
+            );
+        }
+        let body = if self.has_payload() {
+            // A content type of the headers wins
+            if !headers
+                .keys()
+                .any(|name| name.eq_ignore_ascii_case("Content-Type"))
+            {
+                headers.insert("Content-Type".to_string(), self.content_type.clone());
+            }
+            Some(render_template(&self.payload, context))
+        } else {
+            None
+        };
+        TokenRequest {
+            method: self.method.to_ascii_uppercase(),
+            query: render(&self.query),
+            headers,
+            body,
+            timeout: (self.timeout > 0).then(|| Duration::from_millis(self.timeout)),
+            accepted_status: self.accepted_status.clone(),
+        }
+    }
+
+    // urls are the token endpoints, the url is used as is without servers
+    pub fn urls(&self) -> Vec<String> {
+        if self.servers.is_empty() {
+            return vec![self.url.clone()];
+        }
+        self.servers
+            .iter()
+            .map(|server| server.clone() + self.url.as_str())
+            .collect()
+    }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
-#[serde(rename_all = "camelCase")]
+/// A device, a generator of devices with a range, or a file of devices
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct ThingsInfo {
+    // The fields are read from the columns of the file of a file entry, the
+    // validation requires them for the other entries
+    /// Tenant of the device, its payload is the one of the tenant in thingsPayloads
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub tenant_name: String,
 
+    /// Info model of the device, the client id is `infoModelName:thirdThingsId`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub info_model_name: String,
 
+    /// Id of the device
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub third_things_id: String,
 
+    /// Password of the device, rendered in the dynamic token payload
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub password: String,
 
+    /// Extra variables of the topic template and the dynamic token payload
     #[serde(default = "default_hashmap")]
     pub context: HashMap<String, String>,
+
+    /// Generates a device per index of the range, `${index}` or `${index:06}`
+    /// in the other fields is replaced by the index
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<IndexRange>,
+
+    /// Reads the devices from a CSV or JSONL file instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<DeviceFile>,
+}
+
+/// DeviceFile is a device inventory exported as CSV or JSONL, e.g. by a device
+/// registry. A field is read from the column of its name unless it's mapped
+/// in `columns`, the other columns are the context of the device.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DeviceFile {
+    /// Path of the file, relative to the config file
+    pub path: String,
+    /// Format of the file, by the extension of the path if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<FileFormat>,
+    /// Column of a field, e.g. `thirdThingsId: device_id`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub columns: HashMap<String, String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum FileFormat {
+    Csv,
+    Jsonl,
+}
+
+impl FileFormat {
+    pub fn from_path(path: &Path) -> Option<FileFormat> {
+        match path.extension()?.to_str()? {
+            "csv" => Some(FileFormat::Csv),
+            "jsonl" | "ndjson" => Some(FileFormat::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct IndexRange {
+    /// First index
+    #[serde(default)]
+    pub start: u64,
+    /// Number of the generated devices
+    pub count: u64,
 }
 
 impl ThingsInfo {
@@ -118,12 +458,201 @@ impl ThingsInfo {
         insert("password", &self.password, &mut result);
         result
     }
+
+    // to_context is the context of the topic template
+    pub fn to_context<'a>(&'a self, client_id: &'a str) -> HashMap<&'a str, &'a str> {
+        let mut result = self.to_map();
+        result.insert("clientId", client_id);
+        result
+    }
+
+    // count is the number of devices of the entry
+    fn count(&self) -> usize {
+        self.range.map_or(1, |range| range.count as usize)
+    }
+
+    // generate returns the nth device of a generator entry
+    fn generate(&self, offset: usize) -> ThingsInfo {
+        let index = self.range.map_or(0, |range| range.start) + offset as u64;
+        ThingsInfo {
+            tenant_name: expand_index(&self.tenant_name, index),
+            info_model_name: expand_index(&self.info_model_name, index),
+            third_things_id: expand_index(&self.third_things_id, index),
+            password: expand_index(&self.password, index),
+            context: self
+                .context
+                .iter()
+                .map(|(k, v)| (k.clone(), expand_index(v, index)))
+                .collect(),
+            range: None,
+            file: None,
+        }
+    }
+}
+
+// expand_index replaces `${index}` by the index, `${index:06}` pads it with
+// zeros to 6 digits and `${index:6}` with spaces. A placeholder with an
+// invalid width is kept as is.
+pub fn expand_index(pattern: &str, index: u64) -> String {
+    const PREFIX: &str = "${index";
+    let mut out = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+    while let Some(start) = rest.find(PREFIX) {
+        out.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        let Some(end) = placeholder.find('}') else {
+            break;
+        };
+        match &placeholder[PREFIX.len()..end] {
+            "" => out.push_str(&index.to_string()),
+            spec => match spec.strip_prefix(':').map(|w| (w, w.parse::<usize>())) {
+                Some((width, Ok(n))) if width.starts_with('0') => {
+                    out.push_str(&format!("{:0n$}", index))
+                }
+                Some((_, Ok(n))) => out.push_str(&format!("{:n$}", index)),
+                _ => out.push_str(&placeholder[..=end]),
+            },
+        }
+        rest = &placeholder[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// Inventory is the devices of thingsInfo, an entry with a range is expanded
+// into its devices and the devices of a file are read only when a device is
+// used, so a large run doesn't hold every device in memory
+#[derive(Debug, Default, Deserialize)]
+#[serde(from = "Vec<ThingsInfo>")]
+pub struct Inventory {
+    entries: Vec<ThingsInfo>,
+    // reader of every file entry, opened by load
+    readers: Vec<Option<DeviceReader>>,
+    // exclusive end of the device indexes of every entry
+    ends: Vec<usize>,
+}
+
+impl From<Vec<ThingsInfo>> for Inventory {
+    fn from(entries: Vec<ThingsInfo>) -> Inventory {
+        let mut inventory = Inventory {
+            readers: entries.iter().map(|_| None).collect(),
+            entries,
+            ends: vec![],
+        };
+        inventory.count();
+        inventory
+    }
+}
+
+impl PartialEq for Inventory {
+    fn eq(&self, other: &Inventory) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl Serialize for Inventory {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        self.entries.serialize(serializer)
+    }
+}
+
+impl Inventory {
+    pub fn len(&self) -> usize {
+        self.ends.last().copied().unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn entries(&self) -> &[ThingsInfo] {
+        &self.entries
+    }
+
+    // load opens the files of the file entries relative to the directory of
+    // the config, a file entry has no device until it's loaded. It returns
+    // the index of the entry and the error of every file which can't be read.
+    pub fn load(&mut self, dir: &Path) -> Vec<(usize, Error)> {
+        let mut errors = vec![];
+        for (idx, entry) in self.entries.iter().enumerate() {
+            let Some(file) = &entry.file else {
+                continue;
+            };
+            match DeviceReader::open(file, dir) {
+                Ok(reader) => self.readers[idx] = Some(reader),
+                Err(e) => errors.push((idx, e)),
+            }
+        }
+        self.count();
+        errors
+    }
+
+    fn count(&mut self) {
+        self.ends = self
+            .entries
+            .iter()
+            .zip(&self.readers)
+            .scan(0, |end, (entry, reader)| {
+                *end += match (reader, &entry.file) {
+                    (Some(reader), _) => reader.len(),
+                    (None, Some(_)) => 0,
+                    (None, None) => entry.count(),
+                };
+                Some(*end)
+            })
+            .collect();
+    }
+
+    // location returns the index of the entry which holds the device, and
+    // the row of the device in the file of a file entry
+    pub fn location(&self, idx: usize) -> (usize, Option<u64>) {
+        let entry_idx = self.ends.partition_point(|end| *end <= idx);
+        let row = self.readers[entry_idx]
+            .as_ref()
+            .map(|reader| reader.row(idx - self.start(entry_idx)));
+        (entry_idx, row)
+    }
+
+    // indexes returns the device indexes of the entry
+    pub fn indexes(&self, entry_idx: usize) -> Range<usize> {
+        self.start(entry_idx)..self.ends[entry_idx]
+    }
+
+    fn start(&self, entry_idx: usize) -> usize {
+        if entry_idx == 0 {
+            0
+        } else {
+            self.ends[entry_idx - 1]
+        }
+    }
+
+    // device returns the device of the index, it panics if the index is out
+    // of bounds like a slice. The device of a file entry is read from the
+    // file, it fails if the file can't be read anymore.
+    pub fn device(&self, idx: usize) -> Result<Cow<'_, ThingsInfo>> {
+        let (entry_idx, _) = self.location(idx);
+        let entry = &self.entries[entry_idx];
+        let offset = idx - self.start(entry_idx);
+        if let Some(reader) = &self.readers[entry_idx] {
+            return reader.read(offset).map(Cow::Owned).map_err(|e| {
+                Error::other(format!(
+                    "read device of {} failed: {}",
+                    reader.path().display(),
+                    e
+                ))
+            });
+        }
+        if entry.range.is_none() {
+            return Ok(Cow::Borrowed(entry));
+        }
+        Ok(Cow::Owned(entry.generate(offset)))
+    }
 }
 fn insert<'a: 'b, 'b>(k: &'a str, value: &'a str, m: &mut HashMap<&'b str, &'b str>) {
-    if value.len() >= 2
-        && value.char_indices().nth(0).unwrap().1 == '"'
-        && value.char_indices().nth_back(0).unwrap().1 == '"'
-    {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
         m.insert(k, rem_first_and_last(value));
     } else {
         m.insert(k, value);
@@ -137,59 +666,123 @@ fn rem_first_and_last(value: &str) -> &str {
     chars.as_str()
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct Config {
+    /// Brokers as `host:port`, every connection picks one randomly
     #[serde(default = "default_broker_addr")]
     pub broker_addr: Vec<String>,
 
+    /// Prefix of the random client ids
     #[serde(default = "default_client_id")]
     pub client_id: String,
 
+    /// MQTT user name
     #[serde(default = "default_user_name")]
     pub user_name: String,
 
+    /// MQTT password, used when there is no dynamic token
     #[serde(default = "default_password")]
     pub password: String,
 
+    /// Milliseconds between two publishes of a device
     #[serde(default = "default_think_time")]
     pub think_time: i32,
 
+    /// Use a random client id prefixed by clientId instead of `infoModelName:thirdThingsId`
     #[serde(default = "default_random_client_id")]
     pub random_client_id: bool,
 
     #[serde(default = "default_topic_suffix")]
     pub topic_suffix: String,
 
+    /// The payloads are base64 encoded
     #[serde(default = "default_is_payload_base64")]
     pub is_payload_base64: bool,
 
+    /// Payload published by the devices of a tenant, by the tenant name
     #[serde(default = "default_hashmap")]
     pub things_payloads: HashMap<String, String>,
 
+    /// Seconds of the run
     #[serde(default = "default_duration")]
     pub duration: i32,
 
+    /// Devices of the run, a device opens a connection
     #[serde(default = "default_things_info")]
-    pub things_info: Vec<ThingsInfo>,
+    #[schemars(with = "Vec<ThingsInfo>")]
+    pub things_info: Inventory,
 
+    /// Template of the publish topic, rendered with the fields, the context and the clientId of the device
     pub topic_template: String,
 
     #[serde(default = "default_dynamic_token")]
     pub dynamic_token: DynamicToken,
+
+    /// SLO assertions evaluated at the end of the run
+    #[serde(default = "default_thresholds")]
+    pub thresholds: Vec<Threshold>,
+
+    #[serde(default = "default_tls")]
+    pub tls: Tls,
+}
+
+/// Tls enables MQTT over TLS, the broker addresses are still `host:port`
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Tls {
+    #[serde(default)]
+    pub enabled: bool,
+    /// PEM file of the CA certificate, the system roots are trusted if empty
+    #[serde(default)]
+    pub ca_file: String,
+    /// Name verified against the broker certificate, the broker host if empty
+    #[serde(default)]
+    pub server_name: String,
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+/// Threshold is an SLO assertion evaluated at the end of the run, e.g.
+/// `{metric: ackLatencyP99, op: "<", value: 50}`
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Threshold {
+    pub metric: ThresholdMetric,
+    pub op: ThresholdOp,
+    pub value: f64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ThresholdMetric {
+    // PUBLISH -> PUBACK latency percentiles in milliseconds
+    AckLatencyP50,
+    AckLatencyP90,
+    AckLatencyP99,
+    AckLatencyMax,
+    // percentage of the tasks whose connection was accepted by the broker
+    ConnectSuccessRate,
+    // percentage of the acknowledged publishes against the target publishes
+    AchievedRate,
+    FailedTasks,
+    InvalidPubacks,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, JsonSchema)]
+pub enum ThresholdOp {
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
 }
 
 impl Config {
-    pub fn to_context<'a>(
-        &'a self,
-        things_idx: usize,
-        client_id: &'a str,
-    ) -> HashMap<&str, &'a str> {
-        let mut result = self.things_info[things_idx].to_map();
-        result.insert("clientId", client_id);
-        result
-    }
-    pub fn get_client_id(&self, things_idx: usize) -> String {
+    pub fn get_client_id(&self, things: &ThingsInfo) -> String {
         let s: String = rand::thread_rng()
             .sample_iter(&Alphanumeric)
             .take(7)
@@ -200,48 +793,82 @@ impl Config {
         if self.random_client_id {
             return client;
         };
-        let str = self.things_info[things_idx]
-            .info_model_name
-            .to_owned()
-            .clone();
-        let third = &self.things_info[things_idx].third_things_id;
-        str + ":" + third
+        things.info_model_name.clone() + ":" + &things.third_things_id
+    }
+
+    // token_request renders the dynamic token request of a device
+    pub fn token_request(&self, things: &ThingsInfo) -> TokenRequest {
+        self.dynamic_token.render(&things.to_map())
     }
 }
 
 pub async fn get_things_password(
     http_client: &Arc<MyClient>,
     config: &Config,
-    things_idx: usize,
-) -> String {
-    if config.dynamic_token.url == "" {
-        return config.password.clone();
+    things: &ThingsInfo,
+) -> BenchResult<String> {
+    if config.dynamic_token.url.is_empty() {
+        return Ok(config.password.clone());
     }
 
-    let context = config.things_info[things_idx].to_map();
-    let request = render_template(&config.dynamic_token.payload, &context);
-
-    let idx = rand::thread_rng().gen_range(1..config.dynamic_token.servers.len());
-    let url = config.dynamic_token.servers[idx].clone() + config.dynamic_token.url.as_str();
+    let request = config.token_request(things);
+    let urls = config.dynamic_token.urls();
+    let idx = rand::thread_rng().gen_range(0..urls.len());
     http_rpc_call(
         http_client,
-        &url,
+        &urls[idx],
         &request,
         &config.dynamic_token.token_extractor,
     )
     .await
 }
 
+fn default_tls() -> Tls {
+    Tls::default()
+}
+
 fn default_dynamic_token() -> DynamicToken {
     DynamicToken::new()
 }
 
+impl fmt::Display for ThresholdMetric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            ThresholdMetric::AckLatencyP50 => "ackLatencyP50",
+            ThresholdMetric::AckLatencyP90 => "ackLatencyP90",
+            ThresholdMetric::AckLatencyP99 => "ackLatencyP99",
+            ThresholdMetric::AckLatencyMax => "ackLatencyMax",
+            ThresholdMetric::ConnectSuccessRate => "connectSuccessRate",
+            ThresholdMetric::AchievedRate => "achievedRate",
+            ThresholdMetric::FailedTasks => "failedTasks",
+            ThresholdMetric::InvalidPubacks => "invalidPubacks",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for ThresholdOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            ThresholdOp::Lt => "<",
+            ThresholdOp::Le => "<=",
+            ThresholdOp::Gt => ">",
+            ThresholdOp::Ge => ">=",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+fn default_thresholds() -> Vec<Threshold> {
+    Vec::new()
+}
+
 fn default_hashmap() -> HashMap<String, String> {
     HashMap::new()
 }
 
-fn default_things_info() -> Vec<ThingsInfo> {
-    Vec::new()
+fn default_things_info() -> Inventory {
+    Inventory::default()
 }
 
 fn default_meta_label() -> HashMap<String, String> {
@@ -284,6 +911,7 @@ fn default_duration() -> i32 {
     60
 }
 
+#[allow(dead_code)]
 pub trait GroupVersionKind {
     fn group(&self) -> String;
     fn version(&self) -> String;
@@ -304,6 +932,7 @@ impl GroupVersionKind for Stressing {
         match self.spec {
             Spec::Test(_) => "test".to_string(),
             Spec::Publish(_) => "publish".to_string(),
+            Spec::Plan(_) => "plan".to_string(),
         }
     }
 
@@ -312,32 +941,52 @@ impl GroupVersionKind for Stressing {
     }
 }
 
-impl Stressing {
-    pub fn from_file(f: &str) -> Result<Stressing> {
-        match fs::read_to_string(f) {
-            Ok(contents) => spec_from_str(&contents),
-            Err(e) => {
-                println!("parse file {} failed{}", f, e);
-                Err(e)
-            }
-        }
-    }
+// resolve substitutes the environment variables of the config, resolves its
+// includes relative to the directory, upgrades it to the current version and
+// applies the overrides. The notes of the migrations are returned with it.
+pub fn resolve(
+    contents: &str,
+    dir: &Path,
+    overrides: &[Override],
+) -> Result<(String, Vec<String>)> {
+    let contents = preprocess(contents, dir)?;
+    let (contents, notes) = migrate::migrate(&contents)?;
+    Ok((overrides::apply(&contents, overrides)?, notes))
+}
+
+// specs_from_str unmarshals every document of a multi-document YAML, the
+// error is the one of the first document which failed, with its index
+pub fn specs_from_str(
+    contents: &str,
+) -> std::result::Result<Vec<Stressing>, (usize, serde_yaml::Error)> {
+    serde_yaml::Deserializer::from_str(contents)
+        .enumerate()
+        .map(|(idx, document)| Stressing::deserialize(document).map_err(|e| (idx, e)))
+        .collect()
 }
 
+// spec_from_str unmarshals a config of a single document
+#[cfg(test)]
 pub fn spec_from_str(contents: &str) -> Result<Stressing> {
-    match serde_yaml::from_str(contents) {
-        Ok(result) => Ok(result),
-        Err(e) => {
-            println!("unmarshal contents {} error:{}", contents, e);
-            Err(Error::new(ErrorKind::Other, "unmarshal error"))
-        }
+    match specs_from_str(contents) {
+        Ok(mut specs) if specs.len() == 1 => Ok(specs.remove(0)),
+        Ok(specs) => Err(Error::other(format!(
+            "{} documents in the config, expected one",
+            specs.len()
+        ))),
+        Err((_, e)) => Err(Error::other(e)),
     }
 }
 
 #[cfg(test)]
+// The baseline tests predate the get_first and len_zero lints
+#[allow(clippy::get_first, clippy::len_zero)]
 mod tests {
-    use crate::config::{spec_from_str, GroupVersionKind, Spec};
+    use crate::config::{
+        spec_from_str, GroupVersionKind, MetaData, Spec, ThresholdMetric, ThresholdOp,
+    };
     use crate::util::render_template;
+    use std::collections::HashMap;
 
     static YAML_STR: &str = r#"group: github.com/zhao-kun/mqtt-bench
 version: v1.0.1
@@ -419,10 +1068,10 @@ spec:
             Spec::Publish(publish) => publish,
             _ => panic!("should be publish spec"),
         };
-        assert!(config.things_info[0].tenant_name == "google");
-        assert!(config.things_info[0].password == "things_password");
-        assert!(config.things_info[0].third_things_id == "thirdThingsID");
-        assert!(config.things_info[0].info_model_name == "demo_v1");
+        assert!(config.things_info.device(0).unwrap().tenant_name == "google");
+        assert!(config.things_info.device(0).unwrap().password == "things_password");
+        assert!(config.things_info.device(0).unwrap().third_things_id == "thirdThingsID");
+        assert!(config.things_info.device(0).unwrap().info_model_name == "demo_v1");
         assert!(config.topic_template == "/prefix/${tenantName}/${infoModelName}/${thirdThingsId}");
         assert!(config.dynamic_token.url == "/v2/things/mqtt/tokens");
         assert!(
@@ -436,6 +1085,39 @@ spec:
         assert!(config.dynamic_token.servers.get(2).unwrap() == "192.168.1.3");
     }
 
+    #[test]
+    fn thresholds_should_be_unmarshal() {
+        let contents = format!(
+            "{}{}",
+            YAML_STR3,
+            r#"  thresholds:
+  - metric: ackLatencyP99
+    op: "<"
+    value: 50
+  - metric: connectSuccessRate
+    op: ">="
+    value: 99.9
+"#
+        );
+        let config = match spec_from_str(&contents).unwrap().spec {
+            Spec::Publish(publish) => publish,
+            _ => panic!("should be publish spec"),
+        };
+        assert_eq!(config.thresholds.len(), 2);
+        assert_eq!(config.thresholds[0].metric, ThresholdMetric::AckLatencyP99);
+        assert_eq!(config.thresholds[0].op, ThresholdOp::Lt);
+        assert_eq!(config.thresholds[0].value, 50.0);
+        assert_eq!(
+            config.thresholds[1].metric,
+            ThresholdMetric::ConnectSuccessRate
+        );
+        assert_eq!(config.thresholds[1].op, ThresholdOp::Ge);
+        assert_eq!(config.thresholds[1].value, 99.9);
+
+        let invalid = contents.replace("op: \"<\"", "op: \"==\"");
+        assert!(spec_from_str(&invalid).is_err());
+    }
+
     #[test]
     fn spec_should_be_unmarshal2() {
         let spec = spec_from_str(YAML_STR2).unwrap();
@@ -454,7 +1136,8 @@ spec:
         };
         println!("{}", config.dynamic_token.payload);
 
-        let context = config.things_info[0].to_map();
+        let things = config.things_info.device(0).unwrap();
+        let context = things.to_map();
         let request = render_template(&config.dynamic_token.payload, &context);
 
         println!("{}", request);
@@ -468,6 +1151,166 @@ spec:
         assert!(config.things_payloads.len() > 0);
         assert!(config
             .things_payloads
-            .contains_key(&config.things_info[0].tenant_name))
+            .contains_key(&config.things_info.device(0).unwrap().tenant_name))
+    }
+
+    #[test]
+    fn labels_should_be_unmarshal() {
+        let contents = YAML_STR3.replace(
+            "  name: task-demo\n",
+            r#"  name: task-demo
+  label:
+    env: staging
+    broker_version: "5.0"
+    task_name: ignored
+    invalid-name: ignored
+"#,
+        );
+        let spec = spec_from_str(&contents).unwrap();
+        assert_eq!(
+            spec.meta().metric_labels(),
+            vec![
+                ("broker_version".to_string(), "5.0".to_string()),
+                ("env".to_string(), "staging".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn reserved_labels_should_be_ignored() {
+        let meta = MetaData {
+            name: "task-demo".to_string(),
+            label: HashMap::from_iter(
+                ["env", "host", "broker", "tenant", "reason", "phase"]
+                    .map(|k| (k.to_string(), "x".to_string())),
+            ),
+        };
+        assert_eq!(
+            meta.metric_labels(),
+            vec![("env".to_string(), "x".to_string())]
+        );
+    }
+
+    #[test]
+    fn plan_should_be_unmarshal() {
+        use crate::config::{specs_from_str, Plan, PlanMode};
+
+        let documents = specs_from_str(&format!("{}---\n{}", YAML_STR3, YAML_STR)).unwrap();
+        let (name, plan) = Plan::from_documents(documents).unwrap();
+        assert_eq!(name, "task-demo+task-demo");
+        assert_eq!(plan.mode, PlanMode::Concurrent);
+        assert_eq!(plan.specs.len(), 2);
+
+        let contents = r#"group: github.com/zhao-kun/mqtt-bench
+version: v1.0.1
+kind: Plan
+metaData:
+  name: nightly
+spec:
+  mode: sequential
+  specs:
+  - group: github.com/zhao-kun/mqtt-bench
+    version: v1.0.1
+    kind: test
+    metaData:
+      name: warm-up
+    spec:
+      value: 127.0.0.1:1883
+"#;
+        let documents = specs_from_str(contents).unwrap();
+        assert_eq!(documents[0].kind(), "plan");
+        let (name, plan) = Plan::from_documents(documents).unwrap();
+        assert_eq!(name, "nightly");
+        assert_eq!(plan.mode, PlanMode::Sequential);
+        assert_eq!(plan.specs[0].meta().name, "warm-up");
+
+        let documents = specs_from_str(&format!("{}---\n{}", contents, YAML_STR)).unwrap();
+        assert!(Plan::from_documents(documents).is_err());
+
+        // The version of a spec of the plan is checked too
+        let documents =
+            specs_from_str(&contents.replace("    version: v1.0.1", "    version: v2")).unwrap();
+        let e = Plan::from_documents(documents).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "version v2 of warm-up is unknown, the supported versions are v1.0.0, v1.0.1"
+        );
+    }
+
+    #[test]
+    fn unknown_spec_fields_should_be_rejected() {
+        let e = spec_from_str(&YAML_STR.replace("  brokerAddr:", "  brokerAdr:")).unwrap_err();
+        assert!(e.to_string().contains("unknown field `brokerAdr`"), "{}", e);
+
+        for (field, typo) in [
+            ("  name: task-demo", "  nme: task-demo"),
+            ("    method: POST", "    methd: POST"),
+            ("  - tenantName:", "  - tenantNme:"),
+        ] {
+            let e = spec_from_str(&YAML_STR.replace(field, typo)).unwrap_err();
+            let typo = typo
+                .trim_start_matches([' ', '-'])
+                .split(':')
+                .next()
+                .unwrap();
+            assert!(
+                e.to_string().contains(&format!("unknown field `{}`", typo)),
+                "{}",
+                e
+            );
+        }
+
+        let plan = r#"group: github.com/zhao-kun/mqtt-bench
+version: v1.0.1
+kind: plan
+metaData:
+  name: nightly
+spec:
+  thinkTime: 1000
+  specs: []
+"#;
+        let e = spec_from_str(plan).unwrap_err();
+        assert!(e.to_string().contains("unknown field `thinkTime`"), "{}", e);
+    }
+
+    #[test]
+    fn inventory_should_expand_ranges() {
+        use crate::config::{expand_index, Inventory, ThingsInfo};
+
+        assert_eq!(expand_index("device_${index:06}", 42), "device_000042");
+        assert_eq!(expand_index("d${index}-${index:3}", 7), "d7-  7");
+        assert_eq!(expand_index("d${index:x}", 7), "d${index:x}");
+
+        let entries: Vec<ThingsInfo> = serde_yaml::from_str(
+            r#"
+- tenantName: google
+  infoModelName: demo_v1
+  thirdThingsId: fixed
+  password: things_password
+- tenantName: amazon
+  infoModelName: demo_v2
+  thirdThingsId: device_${index:06}
+  password: pw_${index}
+  context:
+    site: site_${index}
+  range: {start: 1, count: 500000}
+"#,
+        )
+        .unwrap();
+        let inventory = Inventory::from(entries);
+        assert_eq!(inventory.len(), 500001);
+        assert_eq!(inventory.device(0).unwrap().third_things_id, "fixed");
+        assert_eq!(inventory.location(500000), (1, None));
+
+        let last = inventory.device(500000).unwrap();
+        assert_eq!(last.third_things_id, "device_500000");
+        assert_eq!(last.password, "pw_500000");
+        assert_eq!(last.context["site"], "site_500000");
+        assert_eq!(last.range, None);
+        assert_eq!(
+            inventory.device(1).unwrap().third_things_id,
+            "device_000001"
+        );
+        assert_eq!(inventory.indexes(1), 1..500001);
     }
 }
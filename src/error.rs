@@ -0,0 +1,211 @@
+use std::fmt;
+use std::io;
+
+use mqtt::control::fixed_header::FixedHeaderError;
+use mqtt::control::ConnectReturnCode;
+use mqtt::packet::VariablePacketError;
+
+// BenchError is the reason a task exits early, every variant is counted as
+// the `reason` label of the `task_failures` metric.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BenchError {
+    // The broker host can't be resolved
+    Dns(String),
+    // The broker refused the TCP connection
+    ConnectRefused(String),
+    // Other TCP connect errors, e.g. timeout or unreachable network
+    Connect(String),
+    // The TLS handshake with the broker failed
+    Tls(String),
+    // The token request didn't get a response
+    TokenRequest(String),
+    // The token server responded with an unexpected status
+    TokenStatus(u16),
+    // The token can't be extracted from the token response
+    TokenExtract(String),
+    // The broker didn't accept the connection, carries the return code
+    Connack(&'static str),
+    // The connection was reset by the broker
+    IoReset(String),
+    // The broker closed the connection
+    Eof,
+    // Other I/O errors on the connection
+    Io(String),
+    // The broker sent an invalid packet or a packet out of order
+    Protocol(String),
+    // The config of the device is invalid, e.g. an invalid topic or payload
+    Config(String),
+    // The run was interrupted by SIGINT or SIGTERM before the task finished
+    Interrupted,
+}
+
+pub type BenchResult<T> = std::result::Result<T, BenchError>;
+
+impl BenchError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            BenchError::Dns(_) => "dns",
+            BenchError::ConnectRefused(_) => "connect_refused",
+            BenchError::Connect(_) => "connect",
+            BenchError::Tls(_) => "tls",
+            BenchError::TokenRequest(_) => "token_request",
+            BenchError::TokenStatus(_) => "token_status",
+            BenchError::TokenExtract(_) => "token_extract",
+            BenchError::Connack(_) => "connack",
+            BenchError::IoReset(_) => "io_reset",
+            BenchError::Eof => "eof",
+            BenchError::Io(_) => "io",
+            BenchError::Protocol(_) => "protocol",
+            BenchError::Config(_) => "config",
+            BenchError::Interrupted => "interrupted",
+        }
+    }
+
+    // is_connect_failure tells whether the broker couldn't be connected,
+    // the token and config errors happen before connecting it
+    pub fn is_connect_failure(&self) -> bool {
+        matches!(
+            self,
+            BenchError::Dns(_)
+                | BenchError::ConnectRefused(_)
+                | BenchError::Connect(_)
+                | BenchError::Tls(_)
+                | BenchError::Connack(_)
+        )
+    }
+
+    // is_retryable tells whether the token request may succeed if it's sent
+    // again, the config and extractor errors and the client errors of the
+    // token server, but for a timeout or throttling, won't
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            BenchError::Config(_) | BenchError::TokenExtract(_) => false,
+            BenchError::TokenStatus(status) => {
+                !(400..500).contains(status) || *status == 408 || *status == 429
+            }
+            _ => true,
+        }
+    }
+
+    // from_connect classifies the error of connecting the broker
+    pub fn from_connect(e: io::Error) -> BenchError {
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => BenchError::ConnectRefused(e.to_string()),
+            _ => BenchError::Connect(e.to_string()),
+        }
+    }
+}
+
+// connack_code is the label of the CONNACK return code
+pub fn connack_code(code: ConnectReturnCode) -> &'static str {
+    match code {
+        ConnectReturnCode::ConnectionAccepted => "accepted",
+        ConnectReturnCode::UnacceptableProtocolVersion => "unacceptable_protocol_version",
+        ConnectReturnCode::IdentifierRejected => "identifier_rejected",
+        ConnectReturnCode::ServiceUnavailable => "server_unavailable",
+        ConnectReturnCode::BadUserNameOrPassword => "bad_username_or_password",
+        ConnectReturnCode::NotAuthorized => "not_authorized",
+        ConnectReturnCode::Reserved(_) => "reserved",
+    }
+}
+
+impl From<io::Error> for BenchError {
+    fn from(e: io::Error) -> BenchError {
+        match e.kind() {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => BenchError::IoReset(e.to_string()),
+            io::ErrorKind::UnexpectedEof => BenchError::Eof,
+            _ => BenchError::Io(e.to_string()),
+        }
+    }
+}
+
+impl From<VariablePacketError> for BenchError {
+    fn from(e: VariablePacketError) -> BenchError {
+        match e {
+            VariablePacketError::IoError(e) => e.into(),
+            VariablePacketError::FixedHeaderError(FixedHeaderError::IoError(e)) => e.into(),
+            e => BenchError::Protocol(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for BenchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BenchError::Dns(e) => write!(f, "resolve broker failed: {}", e),
+            BenchError::ConnectRefused(e) => write!(f, "connection refused: {}", e),
+            BenchError::Connect(e) => write!(f, "connect broker failed: {}", e),
+            BenchError::Tls(e) => write!(f, "TLS handshake failed: {}", e),
+            BenchError::TokenRequest(e) => write!(f, "token request failed: {}", e),
+            BenchError::TokenStatus(status) => write!(f, "token server responded {}", status),
+            BenchError::TokenExtract(e) => write!(f, "extract token failed: {}", e),
+            BenchError::Connack(code) => write!(f, "connection wasn't accepted: {}", code),
+            BenchError::IoReset(e) => write!(f, "connection reset: {}", e),
+            BenchError::Eof => write!(f, "connection closed by the broker"),
+            BenchError::Io(e) => write!(f, "I/O error: {}", e),
+            BenchError::Protocol(e) => write!(f, "protocol error: {}", e),
+            BenchError::Config(e) => write!(f, "invalid config: {}", e),
+            BenchError::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+impl std::error::Error for BenchError {}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use mqtt::packet::VariablePacketError;
+
+    use super::{connack_code, BenchError};
+
+    #[test]
+    fn test_classify_errors() {
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        assert_eq!(
+            BenchError::from_connect(refused).reason(),
+            "connect_refused"
+        );
+        let timeout = io::Error::new(io::ErrorKind::TimedOut, "timeout");
+        assert_eq!(BenchError::from_connect(timeout).reason(), "connect");
+
+        let eof: VariablePacketError = io::Error::new(io::ErrorKind::UnexpectedEof, "eof").into();
+        assert_eq!(BenchError::from(eof), BenchError::Eof);
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        assert_eq!(BenchError::from(reset).reason(), "io_reset");
+        let reserved = VariablePacketError::ReservedPacket(0, vec![]);
+        assert_eq!(BenchError::from(reserved).reason(), "protocol");
+
+        assert!(BenchError::Dns("broker".to_string()).is_connect_failure());
+        assert!(!BenchError::TokenStatus(401).is_connect_failure());
+        assert!(!BenchError::Config("topic".to_string()).is_connect_failure());
+        assert!(!BenchError::Interrupted.is_connect_failure());
+
+        assert!(BenchError::TokenRequest("timeout".to_string()).is_retryable());
+        assert!(BenchError::TokenStatus(503).is_retryable());
+        assert!(BenchError::TokenStatus(429).is_retryable());
+        assert!(!BenchError::TokenStatus(401).is_retryable());
+        assert!(!BenchError::TokenExtract("$.token".to_string()).is_retryable());
+        assert!(!BenchError::Config("url".to_string()).is_retryable());
+    }
+
+    #[test]
+    fn test_connack_code() {
+        use mqtt::control::ConnectReturnCode;
+
+        assert_eq!(
+            connack_code(ConnectReturnCode::ConnectionAccepted),
+            "accepted"
+        );
+        assert_eq!(
+            connack_code(ConnectReturnCode::BadUserNameOrPassword),
+            "bad_username_or_password"
+        );
+        assert_eq!(connack_code(ConnectReturnCode::Reserved(9)), "reserved");
+        let e = BenchError::Connack(connack_code(ConnectReturnCode::NotAuthorized));
+        assert_eq!(e.to_string(), "connection wasn't accepted: not_authorized");
+    }
+}
@@ -0,0 +1,212 @@
+use std::fs::OpenOptions;
+use std::io::Result;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, oneshot};
+
+// EVENT_LOG is installed when the lifecycle events are logged, the lines are
+// written by the writer task so the connections never wait for the file
+static EVENT_LOG: OnceLock<mpsc::UnboundedSender<Message>> = OnceLock::new();
+
+// Interval of flushing the buffered events to the file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Message {
+    Line(String),
+    // Flush notifies the sender once the lines sent before are written
+    Flush(oneshot::Sender<()>),
+}
+
+pub fn args() -> Vec<clap::Arg> {
+    vec![clap::arg!(--"event-log" <PATH>)
+        .value_parser(clap::value_parser!(std::path::PathBuf))
+        .help("Write a JSON line per connection lifecycle event to the file")]
+}
+
+// Event is a lifecycle event of a connection, it is written as the `event`
+// field of the line with the fields of the variant.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    TokenRequested,
+    #[serde(rename_all = "camelCase")]
+    TokenObtained {
+        elapsed_ms: f64,
+    },
+    #[serde(rename_all = "camelCase")]
+    TokenFailed {
+        elapsed_ms: f64,
+        error: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    TcpConnected {
+        broker: String,
+        elapsed_ms: f64,
+    },
+    #[serde(rename_all = "camelCase")]
+    TcpFailed {
+        broker: String,
+        elapsed_ms: f64,
+        error: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Connack {
+        code: String,
+        elapsed_ms: f64,
+    },
+    FirstPublish,
+    Disconnect {
+        reason: String,
+    },
+    TaskExit {
+        reason: String,
+        sent: usize,
+        acked: usize,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Line<'a> {
+    timestamp: String,
+    client_id: &'a str,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+pub fn install(path: &Path) -> Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = EVENT_LOG.set(spawn_writer(tokio::fs::File::from_std(file)));
+    Ok(())
+}
+
+// flush writes the buffered events to the file
+pub async fn flush() {
+    if let Some(tx) = EVENT_LOG.get() {
+        flush_writer(tx).await;
+    }
+}
+
+async fn flush_writer(tx: &mpsc::UnboundedSender<Message>) {
+    let (done, flushed) = oneshot::channel();
+    if tx.send(Message::Flush(done)).is_ok() {
+        let _ = flushed.await;
+    }
+}
+
+// spawn_writer starts the task writing the lines to the file, the file is
+// flushed periodically and on demand
+fn spawn_writer(file: tokio::fs::File) -> mpsc::UnboundedSender<Message> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut writer = BufWriter::new(file);
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                message = rx.recv() => match message {
+                    Some(Message::Line(line)) => {
+                        if let Err(e) = writer.write_all(line.as_bytes()).await {
+                            tracing::warn!(error = %e, "write event log failed");
+                        }
+                    }
+                    Some(Message::Flush(done)) => {
+                        flush_file(&mut writer).await;
+                        let _ = done.send(());
+                    }
+                    None => {
+                        flush_file(&mut writer).await;
+                        return;
+                    }
+                },
+                _ = interval.tick() => flush_file(&mut writer).await,
+            }
+        }
+    });
+    tx
+}
+
+async fn flush_file(writer: &mut BufWriter<tokio::fs::File>) {
+    if let Err(e) = writer.flush().await {
+        tracing::warn!(error = %e, "flush event log failed");
+    }
+}
+
+fn format_line(timestamp: OffsetDateTime, client_id: &str, event: &Event) -> String {
+    let line = Line {
+        timestamp: timestamp.format(&Rfc3339).unwrap_or_default(),
+        client_id,
+        event,
+    };
+    serde_json::to_string(&line).unwrap_or_default()
+}
+
+pub fn millis(elapsed: Duration) -> f64 {
+    elapsed.as_secs_f64() * 1000.0
+}
+
+// ConnectionEvents writes the lifecycle events of a single connection, it
+// does nothing when the event log isn't installed.
+pub struct ConnectionEvents {
+    client_id: String,
+}
+
+impl ConnectionEvents {
+    pub fn new(client_id: &str) -> ConnectionEvents {
+        ConnectionEvents {
+            client_id: client_id.to_string(),
+        }
+    }
+
+    pub fn emit(&self, event: Event) {
+        let Some(tx) = EVENT_LOG.get() else {
+            return;
+        };
+        let mut line = format_line(OffsetDateTime::now_utc(), &self.client_id, &event);
+        line.push('\n');
+        let _ = tx.send(Message::Line(line));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::{flush_writer, format_line, spawn_writer, Event, Message};
+
+    #[test]
+    fn test_format_line() {
+        let timestamp = datetime!(2023-02-01 08:30:00.25 UTC);
+        assert_eq!(
+            format_line(timestamp, "device_1", &Event::TokenRequested),
+            r#"{"timestamp":"2023-02-01T08:30:00.25Z","clientId":"device_1","event":"token_requested"}"#
+        );
+        let event = Event::TcpFailed {
+            broker: "10.0.0.1:1883".to_string(),
+            elapsed_ms: 1.5,
+            error: "Connection refused".to_string(),
+        };
+        assert_eq!(
+            format_line(timestamp, "device_1", &event),
+            r#"{"timestamp":"2023-02-01T08:30:00.25Z","clientId":"device_1","event":"tcp_failed","broker":"10.0.0.1:1883","elapsedMs":1.5,"error":"Connection refused"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flush_writes_the_sent_lines() {
+        let path =
+            std::env::temp_dir().join(format!("mqtt-bench-events-{}.log", std::process::id()));
+        let file = tokio::fs::File::create(&path).await.unwrap();
+        let tx = spawn_writer(file);
+        tx.send(Message::Line("first\n".to_string())).ok().unwrap();
+        tx.send(Message::Line("second\n".to_string())).ok().unwrap();
+        flush_writer(&tx).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
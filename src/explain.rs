@@ -0,0 +1,346 @@
+use std::fmt::Write as _;
+
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use crate::config::{GroupVersionKind, Plan, Stressing};
+use crate::overrides;
+use crate::validate;
+
+// Comment marking a value which isn't in the config
+const DEFAULT_MARK: &str = "  # default";
+
+// Value replacing the credentials of a redacted config
+const REDACTED: &str = "<redacted>";
+
+// Header names carrying credentials, matched case-insensitively as a part of
+// the name, e.g. `Authorization` or `X-Api-Key`
+const SECRET_NAMES: [&str; 3] = ["authorization", "key", "token"];
+
+// explain returns the YAML of the spec with every default applied, the
+// values which aren't in the given document are marked by `# default`. A
+// redacted config has no credentials, e.g. for a report which is shared.
+pub fn explain(stressing: &Stressing, document: &Value, redacted: bool) -> String {
+    let mut effective = serde_yaml::to_value(stressing).unwrap_or(Value::Null);
+    if redacted {
+        redact(&mut effective);
+    }
+    let mut out = String::new();
+    emit(&mut out, &effective, Some(document), 0);
+    out
+}
+
+// explain_plan explains every spec of the plan parsed from the contents, the
+// contents are the config after the environment variables, includes and
+// overrides
+pub fn explain_plan(contents: &str, plan: &Plan, redacted: bool) -> Vec<String> {
+    let mut documents: Vec<Value> = serde_yaml::Deserializer::from_str(contents)
+        .map(|document| Value::deserialize(document).unwrap_or(Value::Null))
+        .collect();
+    // The specs of a plan document are listed in it
+    let is_plan = |document: &Value| {
+        document
+            .get("kind")
+            .and_then(Value::as_str)
+            .is_some_and(|kind| kind.eq_ignore_ascii_case("plan"))
+    };
+    if documents.len() == 1 && is_plan(&documents[0]) {
+        documents = match documents
+            .remove(0)
+            .get("spec")
+            .and_then(|spec| spec.get("specs"))
+        {
+            Some(Value::Sequence(specs)) => specs.clone(),
+            _ => vec![],
+        };
+    }
+    plan.specs
+        .iter()
+        .enumerate()
+        .map(|(idx, stressing)| {
+            explain(
+                stressing,
+                documents.get(idx).unwrap_or(&Value::Null),
+                redacted,
+            )
+        })
+        .collect()
+}
+
+// redact replaces every password, e.g. of the devices and of the basic auth
+// of the token request, and the values of the headers and query parameters
+// carrying credentials
+fn redact(value: &mut Value) {
+    match value {
+        Value::Mapping(mapping) => {
+            for (key, child) in mapping.iter_mut() {
+                match (key.as_str(), child) {
+                    (Some("password"), child @ Value::String(_)) => *child = REDACTED.into(),
+                    (Some("headers" | "query"), Value::Mapping(params)) => {
+                        for (name, param) in params.iter_mut() {
+                            if name.as_str().is_some_and(is_secret_name) {
+                                *param = REDACTED.into();
+                            }
+                        }
+                    }
+                    (_, child) => redact(child),
+                }
+            }
+        }
+        Value::Sequence(sequence) => sequence.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn is_secret_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SECRET_NAMES.iter().any(|secret| name.contains(secret))
+}
+
+// emit writes the value at the indent, `given` is the same value in the
+// config, None if the whole value is a default
+fn emit(out: &mut String, value: &Value, given: Option<&Value>, indent: usize) {
+    let pad = " ".repeat(indent);
+    match value {
+        Value::Mapping(mapping) => {
+            for (key, child) in mapping {
+                let given_child = match given {
+                    Some(Value::Mapping(given)) => given.get(key),
+                    _ => None,
+                };
+                let mark = if given.is_some() && given_child.is_none() {
+                    DEFAULT_MARK
+                } else {
+                    ""
+                };
+                let _ = write!(out, "{}{}:", pad, scalar(key));
+                emit_child(out, child, given_child, indent, mark);
+            }
+        }
+        Value::Sequence(sequence) => {
+            for (idx, item) in sequence.iter().enumerate() {
+                let given_item = match given {
+                    Some(Value::Sequence(given)) => given.get(idx),
+                    _ => None,
+                };
+                if is_collection(item) && !is_empty(item) {
+                    // The first line of the item follows the dash
+                    let mut item_out = String::new();
+                    emit(&mut item_out, item, given_item, indent + 2);
+                    let _ = write!(out, "{}- {}", pad, &item_out[indent + 2..]);
+                } else {
+                    let _ = write!(out, "{}-", pad);
+                    emit_child(out, item, given_item, indent, "");
+                }
+            }
+        }
+        value => {
+            let _ = writeln!(out, "{}{}", pad, scalar(value));
+        }
+    }
+}
+
+// emit_child writes the value of a key or an item after its colon or dash
+fn emit_child(out: &mut String, value: &Value, given: Option<&Value>, indent: usize, mark: &str) {
+    // The children of a default aren't marked again
+    let given = if mark.is_empty() { given } else { None };
+    if is_collection(value) && !is_empty(value) {
+        let _ = writeln!(out, "{}", mark);
+        // The items of a sequence are at the indent of their key
+        let indent = if value.is_sequence() {
+            indent
+        } else {
+            indent + 2
+        };
+        emit(out, value, given, indent);
+        return;
+    }
+    let text = scalar(value);
+    let mut lines = text.lines();
+    let _ = writeln!(out, " {}{}", lines.next().unwrap_or_default(), mark);
+    // The lines of a block scalar are indented relative to its key
+    let pad = " ".repeat(indent);
+    for line in lines {
+        let _ = writeln!(out, "{}{}", pad, line);
+    }
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::Mapping(_) => "{}".to_string(),
+        Value::Sequence(_) => "[]".to_string(),
+        value => serde_yaml::to_string(value)
+            .unwrap_or_default()
+            .trim_end()
+            .to_string(),
+    }
+}
+
+fn is_collection(value: &Value) -> bool {
+    value.is_mapping() || value.is_sequence()
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Mapping(mapping) => mapping.is_empty(),
+        Value::Sequence(sequence) => sequence.is_empty(),
+        _ => false,
+    }
+}
+
+pub fn command() -> clap::Command {
+    clap::Command::new("explain")
+        .about("Print the effective config with the defaults, environment variables, includes and overrides applied")
+        .arg(
+            clap::arg!(--"file" <PATH>)
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .short(Some('f'))
+                .required(true)
+                .help("Config file for stress test"),
+        )
+        .args(overrides::args())
+}
+
+// execute prints the effective config of every spec as a YAML document
+pub fn execute(matches: &clap::ArgMatches) -> i32 {
+    let path = matches.get_one::<std::path::PathBuf>("file").unwrap();
+    let config = match validate::read(path, &overrides::from_matches(matches)) {
+        Ok(config) => config,
+        Err(code) => return code,
+    };
+    let plan = match config.parse() {
+        Ok((_, plan)) => plan,
+        Err(issue) => {
+            eprintln!("{}: {}", path.display(), issue);
+            return 1;
+        }
+    };
+    let explained = explain_plan(&config.contents, &plan, false);
+    for (idx, (stressing, explained)) in plan.specs.iter().zip(explained).enumerate() {
+        if idx > 0 {
+            println!("---");
+        }
+        if plan.specs.len() > 1 {
+            println!("# {}", stressing.meta().name);
+        }
+        print!("{}", explained);
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::explain_plan;
+    use crate::config::{specs_from_str, Plan};
+
+    const YAML: &str = r#"group: github.com/zhao-kun/mqtt-bench
+version: v1.0.1
+kind: publish
+metaData:
+  name: task-demo
+spec:
+  brokerAddr: ["127.0.0.1:1883"]
+  dynamicToken:
+    url: http://localhost:8080/v1/
+    payload: '{"username": "${tenantName}"}'
+    tokenExtractor: ".data.token"
+  topicTemplate: /prefix/${tenantName}
+  thingsPayloads:
+    google: aGVsbG8=
+  thingsInfo:
+  - tenantName: google
+    infoModelName: demo_v1
+    thirdThingsId: device_${index:06}
+    password: things_password
+    range: {count: 2}
+"#;
+
+    #[test]
+    fn test_explain() {
+        let (_, plan) = Plan::from_documents(specs_from_str(YAML).unwrap()).unwrap();
+        let explained = explain_plan(YAML, &plan, false);
+        assert_eq!(
+            explained[0],
+            r#"group: github.com/zhao-kun/mqtt-bench
+version: v1.0.1
+metaData:
+  name: task-demo
+  label: {}  # default
+kind: publish
+spec:
+  brokerAddr:
+  - 127.0.0.1:1883
+  clientId: test  # default
+  userName: admin  # default
+  password: admin  # default
+  thinkTime: 30000  # default
+  randomClientId: false  # default
+  topicSuffix: ''  # default
+  isPayloadBase64: true  # default
+  thingsPayloads:
+    google: aGVsbG8=
+  duration: 60  # default
+  thingsInfo:
+  - tenantName: google
+    infoModelName: demo_v1
+    thirdThingsId: device_${index:06}
+    password: things_password
+    context: {}  # default
+    range:
+      start: 0  # default
+      count: 2
+  topicTemplate: /prefix/${tenantName}
+  dynamicToken:
+    url: http://localhost:8080/v1/
+    payload: '{"username": "${tenantName}"}'
+    tokenExtractor: .data.token
+    method: POST  # default
+    servers: []  # default
+    query: {}  # default
+    headers: {}  # default
+    contentType: application/json  # default
+    timeout: 10000  # default
+    acceptedStatus:  # default
+    - 200
+  thresholds: []  # default
+  tls:  # default
+    enabled: false
+    caFile: ''
+    serverName: ''
+    insecureSkipVerify: false
+"#
+        );
+
+        // The parsed config is valid YAML
+        let (_, reparsed) = Plan::from_documents(specs_from_str(&explained[0]).unwrap()).unwrap();
+        assert_eq!(reparsed, plan);
+    }
+
+    #[test]
+    fn test_explain_redacted() {
+        let contents = YAML.replace(
+            "    tokenExtractor: \".data.token\"\n",
+            "    tokenExtractor: \".data.token\"\n    query:\n      api_key: secret\n      access_token: secret\n      page: \"1\"\n    headers:\n      X-Api-Key: secret\n      Accept: application/json\n    basicAuth:\n      username: ${tenantName}\n      password: ${password}\n",
+        );
+        let (_, plan) = Plan::from_documents(specs_from_str(&contents).unwrap()).unwrap();
+        let explained = explain_plan(&contents, &plan, true);
+        for redacted in [
+            "  password: <redacted>  # default\n",
+            "    password: <redacted>\n    context",
+            "      X-Api-Key: <redacted>\n",
+            "      api_key: <redacted>\n",
+            "      access_token: <redacted>\n",
+            "      password: <redacted>\n",
+        ] {
+            assert!(explained[0].contains(redacted), "{}", explained[0]);
+        }
+        assert!(explained[0].contains("      Accept: application/json\n"));
+        assert!(
+            explained[0].contains("      page: '1'\n"),
+            "{}",
+            explained[0]
+        );
+        assert!(explained[0].contains("      username: ${tenantName}\n"));
+        assert!(!explained[0].contains("things_password"));
+    }
+}
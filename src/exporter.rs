@@ -0,0 +1,126 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder};
+use metrics_util::layers::{PrefixLayer, Stack};
+use metrics_util::MetricKindMask;
+
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:9000";
+const DEFAULT_IDLE_TIMEOUT_SECS: &str = "10";
+
+// Buckets in seconds of the latency histograms, all latency histograms are
+// named with the `_seconds` suffix.
+const DEFAULT_LATENCY_BUCKETS: [f64; 14] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+#[derive(Debug, Clone)]
+pub struct ExporterOptions {
+    pub listen_addr: SocketAddr,
+    pub prefix: Option<String>,
+    pub idle_timeout: Option<Duration>,
+    pub latency_buckets: Vec<f64>,
+}
+
+pub fn args() -> Vec<clap::Arg> {
+    vec![
+        clap::arg!(--"metrics-listen" <ADDR>)
+            .value_parser(clap::value_parser!(SocketAddr))
+            .default_value(DEFAULT_LISTEN_ADDR)
+            .help("Listen address of the Prometheus scrape endpoint"),
+        clap::arg!(--"metrics-prefix" <PREFIX>)
+            .help("Prefix of all exported metric names, e.g. mqtt_bench"),
+        clap::arg!(--"metrics-idle-timeout" <SECS>)
+            .value_parser(clap::value_parser!(u64))
+            .default_value(DEFAULT_IDLE_TIMEOUT_SECS)
+            .help("Seconds before an unchanged metric is removed, 0 keeps metrics forever"),
+        clap::arg!(--"latency-buckets" <SECS>)
+            .value_parser(clap::value_parser!(f64))
+            .value_delimiter(',')
+            .help("Comma separated buckets in seconds of the latency histograms"),
+    ]
+}
+
+impl ExporterOptions {
+    pub fn from_matches(matches: &clap::ArgMatches) -> ExporterOptions {
+        let idle_timeout = *matches.get_one::<u64>("metrics-idle-timeout").unwrap();
+        let latency_buckets = match matches.get_many::<f64>("latency-buckets") {
+            Some(buckets) => buckets.copied().collect(),
+            None => DEFAULT_LATENCY_BUCKETS.to_vec(),
+        };
+        ExporterOptions {
+            listen_addr: *matches.get_one::<SocketAddr>("metrics-listen").unwrap(),
+            prefix: matches.get_one::<String>("metrics-prefix").cloned(),
+            idle_timeout: if idle_timeout == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(idle_timeout))
+            },
+            latency_buckets,
+        }
+    }
+}
+
+// install starts the Prometheus scrape endpoint and installs the global
+// metrics recorder, it must be called inside the tokio runtime.
+pub fn install(options: &ExporterOptions) -> Result<(), BuildError> {
+    let (recorder, exporter) = PrometheusBuilder::new()
+        .with_http_listener(options.listen_addr)
+        .idle_timeout(
+            MetricKindMask::COUNTER | MetricKindMask::GAUGE,
+            options.idle_timeout,
+        )
+        .set_buckets_for_metric(
+            Matcher::Suffix("_seconds".to_string()),
+            &options.latency_buckets,
+        )?
+        .build()?;
+
+    let stack = Stack::new(recorder);
+    match &options.prefix {
+        Some(prefix) => stack.push(PrefixLayer::new(prefix.clone())).install()?,
+        None => stack.install()?,
+    }
+    tokio::spawn(exporter);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{args, ExporterOptions, DEFAULT_LATENCY_BUCKETS};
+
+    fn parse(argv: &[&str]) -> ExporterOptions {
+        let matches = clap::Command::new("test")
+            .args(args())
+            .get_matches_from(argv);
+        ExporterOptions::from_matches(&matches)
+    }
+
+    #[test]
+    fn test_default_options() {
+        let options = parse(&["test"]);
+        assert_eq!(options.listen_addr.to_string(), "0.0.0.0:9000");
+        assert_eq!(options.prefix, None);
+        assert_eq!(options.idle_timeout.unwrap().as_secs(), 10);
+        assert_eq!(options.latency_buckets, DEFAULT_LATENCY_BUCKETS.to_vec());
+    }
+
+    #[test]
+    fn test_options() {
+        let options = parse(&[
+            "test",
+            "--metrics-listen",
+            "127.0.0.1:9100",
+            "--metrics-prefix",
+            "mqtt_bench",
+            "--metrics-idle-timeout",
+            "0",
+            "--latency-buckets",
+            "0.01,0.1,1",
+        ]);
+        assert_eq!(options.listen_addr.to_string(), "127.0.0.1:9100");
+        assert_eq!(options.prefix.as_deref(), Some("mqtt_bench"));
+        assert_eq!(options.idle_timeout, None);
+        assert_eq!(options.latency_buckets, vec![0.01, 0.1, 1.0]);
+    }
+}
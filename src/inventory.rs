@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, Result, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::config::{DeviceFile, FileFormat, ThingsInfo};
+
+// Fields of a device, a row must have the required ones
+const REQUIRED_FIELDS: [&str; 3] = ["tenantName", "infoModelName", "thirdThingsId"];
+const PASSWORD_FIELD: &str = "password";
+
+// DeviceReader reads the devices of a CSV or JSONL file. The file is scanned
+// once for the offset of every row, a device is only read when it's used, so
+// a large inventory isn't held in memory. The file stays open, the readers
+// share it.
+#[derive(Debug)]
+pub struct DeviceReader {
+    path: PathBuf,
+    file: Mutex<File>,
+    format: FileFormat,
+    // field -> column, a field is read from the column of its name if absent
+    columns: HashMap<String, String>,
+    // header of a CSV file
+    headers: Vec<String>,
+    // byte offset and row number of every device
+    rows: Vec<(u64, u64)>,
+}
+
+impl DeviceReader {
+    // open scans the file, the error of an invalid row has its row number
+    pub fn open(device_file: &DeviceFile, dir: &Path) -> Result<DeviceReader> {
+        let path = dir.join(&device_file.path);
+        let format = match device_file.format {
+            Some(format) => format,
+            None => FileFormat::from_path(&path)
+                .ok_or_else(|| Error::other("unknown format, the file should be .csv or .jsonl"))?,
+        };
+        let file = File::open(&path)?;
+        // The scan reads through a handle of the same file, a read seeks to its row
+        let scanned = file.try_clone()?;
+        let mut reader = DeviceReader {
+            path,
+            file: Mutex::new(file),
+            format,
+            columns: device_file.columns.clone(),
+            headers: vec![],
+            rows: vec![],
+        };
+        match format {
+            FileFormat::Csv => reader.scan_csv(scanned)?,
+            FileFormat::Jsonl => reader.scan_jsonl(scanned)?,
+        }
+        Ok(reader)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // row returns the row number of the device in the file
+    pub fn row(&self, idx: usize) -> u64 {
+        self.rows[idx].1
+    }
+
+    // read returns the device of the index. It blocks, the tasks read their
+    // device off the runtime.
+    pub fn read(&self, idx: usize) -> Result<ThingsInfo> {
+        let (offset, row) = self.rows[idx];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        let values = match self.format {
+            FileFormat::Csv => {
+                let mut record = csv::StringRecord::new();
+                csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    .from_reader(&mut *file)
+                    .read_record(&mut record)
+                    .map_err(Error::other)?;
+                self.csv_values(&record)
+            }
+            FileFormat::Jsonl => {
+                let mut line = String::new();
+                BufReader::new(&mut *file).read_line(&mut line)?;
+                jsonl_values(&line)?
+            }
+        };
+        self.things(values)
+            .map_err(|e| Error::other(format!("row {}: {}", row, e)))
+    }
+
+    fn scan_csv(&mut self, file: File) -> Result<()> {
+        let mut reader = csv::Reader::from_reader(BufReader::new(file));
+        let invalid = |e: csv::Error| {
+            let row = e.position().map_or(1, |position| position.line());
+            Error::other(format!("row {}: {}", row, e))
+        };
+        self.headers = reader
+            .headers()
+            .map_err(invalid)?
+            .iter()
+            .map(String::from)
+            .collect();
+        for field in REQUIRED_FIELDS {
+            let column = self.column(field);
+            if !self.headers.iter().any(|header| header == column) {
+                return Err(Error::other(format!("row 1: no column {}", column)));
+            }
+        }
+        let mut record = csv::StringRecord::new();
+        while reader.read_record(&mut record).map_err(invalid)? {
+            let position = record.position().expect("position of a read record");
+            let row = position.line();
+            self.things(self.csv_values(&record))
+                .map_err(|e| Error::other(format!("row {}: {}", row, e)))?;
+            self.rows.push((position.byte(), row));
+        }
+        Ok(())
+    }
+
+    fn scan_jsonl(&mut self, file: File) -> Result<()> {
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        let mut offset = 0;
+        let mut row = 0;
+        loop {
+            line.clear();
+            let len = reader.read_line(&mut line)?;
+            if len == 0 {
+                return Ok(());
+            }
+            row += 1;
+            if !line.trim().is_empty() {
+                jsonl_values(&line)
+                    .and_then(|values| self.things(values))
+                    .map_err(|e| Error::other(format!("row {}: {}", row, e)))?;
+                self.rows.push((offset, row));
+            }
+            offset += len as u64;
+        }
+    }
+
+    fn column<'a>(&'a self, field: &'a str) -> &'a str {
+        self.columns.get(field).map_or(field, String::as_str)
+    }
+
+    fn csv_values(&self, record: &csv::StringRecord) -> HashMap<String, String> {
+        self.headers
+            .iter()
+            .cloned()
+            .zip(record.iter().map(String::from))
+            .collect()
+    }
+
+    // things maps the columns to the fields of a device, the other columns
+    // are its context
+    fn things(&self, mut values: HashMap<String, String>) -> Result<ThingsInfo> {
+        let mut take = |field: &str| values.remove(self.column(field));
+        let mut required = REQUIRED_FIELDS.map(|field| (field, take(field)));
+        if let Some((field, _)) = required
+            .iter()
+            .find(|(_, value)| value.as_deref().unwrap_or_default().is_empty())
+        {
+            return Err(Error::other(format!("no {}", self.column(field))));
+        }
+        let password = take(PASSWORD_FIELD).unwrap_or_default();
+        let mut required = required.iter_mut().map(|(_, value)| value.take().unwrap());
+        Ok(ThingsInfo {
+            tenant_name: required.next().unwrap(),
+            info_model_name: required.next().unwrap(),
+            third_things_id: required.next().unwrap(),
+            password,
+            context: values,
+            ..Default::default()
+        })
+    }
+}
+
+fn jsonl_values(line: &str) -> Result<HashMap<String, String>> {
+    let values: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(line).map_err(Error::other)?;
+    Ok(values
+        .into_iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(value) => (key, value),
+            value => (key, value.to_string()),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use super::DeviceReader;
+    use crate::config::DeviceFile;
+
+    #[test]
+    fn test_read_devices() {
+        let dir = std::env::temp_dir().join(format!("mqtt-bench-inventory-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("devices.csv"),
+            "tenant,infoModelName,thirdThingsId,password,site\ngoogle,demo_v1,device_1,pw,\"north\nside\"\namazon,demo_v2,device_2,pw,south\n",
+        )
+        .unwrap();
+        let device_file = DeviceFile {
+            path: "devices.csv".to_string(),
+            format: None,
+            columns: HashMap::from([("tenantName".to_string(), "tenant".to_string())]),
+        };
+        let reader = DeviceReader::open(&device_file, &dir).unwrap();
+        assert_eq!(reader.len(), 2);
+        let device = reader.read(1).unwrap();
+        assert_eq!(device.tenant_name, "amazon");
+        assert_eq!(device.context["site"], "south");
+        assert_eq!(reader.read(0).unwrap().context["site"], "north\nside");
+        assert_eq!(reader.row(1), 4);
+        // The file changed since it was scanned
+        fs::write(dir.join("devices.csv"), "tenant\n").unwrap();
+        assert!(reader.read(1).is_err());
+
+        fs::write(
+            dir.join("devices.jsonl"),
+            "{\"tenantName\": \"google\", \"infoModelName\": \"demo_v1\", \"thirdThingsId\": \"device_1\", \"floor\": 3}\n\n{\"tenantName\": \"amazon\", \"infoModelName\": \"demo_v2\"}\n",
+        )
+        .unwrap();
+        let device_file = DeviceFile {
+            path: "devices.jsonl".to_string(),
+            ..Default::default()
+        };
+        let e = DeviceReader::open(&device_file, &dir).unwrap_err();
+        assert_eq!(e.to_string(), "row 3: no thirdThingsId");
+
+        fs::write(dir.join("devices.jsonl"), "{\"tenantName\": \"google\", \"infoModelName\": \"demo_v1\", \"thirdThingsId\": \"device_1\", \"floor\": 3}\n").unwrap();
+        let reader = DeviceReader::open(&device_file, &dir).unwrap();
+        let device = reader.read(0).unwrap();
+        assert_eq!(device.third_things_id, "device_1");
+        assert_eq!(device.password, "");
+        assert_eq!(device.context["floor"], "3");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
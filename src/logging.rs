@@ -0,0 +1,171 @@
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::prelude::*;
+
+use crate::util;
+
+// Target of the packet level logs, they are only emitted for the client ids
+// matching `--trace-client`.
+pub const PACKET_TARGET: &str = "mqtt_bench::packet";
+
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_LOG_FORMAT: &str = "text";
+
+static TRACE_CLIENTS: OnceLock<Vec<String>> = OnceLock::new();
+
+// packet_log logs a packet level event at debug level if the client is traced
+#[macro_export]
+macro_rules! packet_log {
+    ($traced:expr, $($arg:tt)*) => {
+        if $traced {
+            tracing::debug!(target: $crate::logging::PACKET_TARGET, $($arg)*);
+        }
+    };
+}
+
+#[derive(Debug, Clone)]
+pub struct LogOptions {
+    pub level: LevelFilter,
+    pub json: bool,
+    pub file: Option<PathBuf>,
+    pub trace_clients: Vec<String>,
+}
+
+pub fn args() -> Vec<clap::Arg> {
+    vec![
+        clap::arg!(--"log-level" <LEVEL>)
+            .value_parser(["error", "warn", "info", "debug", "trace"])
+            .default_value(DEFAULT_LOG_LEVEL)
+            .help("Level of the logs"),
+        clap::arg!(--"log-format" <FORMAT>)
+            .value_parser(["text", "json"])
+            .default_value(DEFAULT_LOG_FORMAT)
+            .help("Format of the logs, json writes a JSON object per line"),
+        clap::arg!(--"log-file" <PATH>)
+            .value_parser(clap::value_parser!(PathBuf))
+            .help("Write the logs to the file instead of stdout"),
+        clap::arg!(--"trace-client" <PATTERN>)
+            .action(clap::ArgAction::Append)
+            .help("Log the packets of the client ids matching the pattern, `*` matches any characters"),
+    ]
+}
+
+impl LogOptions {
+    pub fn from_matches(matches: &clap::ArgMatches) -> LogOptions {
+        let level = matches.get_one::<String>("log-level").unwrap();
+        LogOptions {
+            level: level.parse().unwrap_or(LevelFilter::INFO),
+            json: matches.get_one::<String>("log-format").unwrap() == "json",
+            file: matches.get_one::<PathBuf>("log-file").cloned(),
+            trace_clients: matches
+                .get_many::<String>("trace-client")
+                .map(|patterns| patterns.cloned().collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+// ConsoleWriter writes to stdout unless the console output is quiet, e.g.
+// when the terminal is owned by the dashboard.
+struct ConsoleWriter;
+
+impl Write for ConsoleWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if util::is_quiet() {
+            return Ok(buf.len());
+        }
+        io::stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+// init installs the global subscriber, the logs are written by a background
+// thread which flushes the pending logs when the returned guard is dropped.
+pub fn init(options: &LogOptions) -> io::Result<WorkerGuard> {
+    let _ = TRACE_CLIENTS.set(options.trace_clients.clone());
+
+    let (writer, guard) = match &options.file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            tracing_appender::non_blocking(file)
+        }
+        None => tracing_appender::non_blocking(ConsoleWriter),
+    };
+    let filter = Targets::new()
+        .with_default(options.level)
+        .with_target(PACKET_TARGET, LevelFilter::DEBUG);
+
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(options.file.is_none());
+    let layer = if options.json {
+        layer.json().boxed()
+    } else {
+        layer.boxed()
+    };
+    tracing_subscriber::registry()
+        .with(layer.with_filter(filter))
+        .try_init()
+        .map_err(io::Error::other)?;
+    Ok(guard)
+}
+
+// is_traced tells whether the packet level logs of the client are enabled
+pub fn is_traced(client_id: &str) -> bool {
+    TRACE_CLIENTS
+        .get()
+        .map(|patterns| patterns.iter().any(|p| wildcard_match(p, client_id)))
+        .unwrap_or(false)
+}
+
+// wildcard_match matches the text against the pattern, `*` matches any
+// characters and `?` matches a single character.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::wildcard_match;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("device_1", "device_1"));
+        assert!(!wildcard_match("device_1", "device_12"));
+        assert!(wildcard_match("device_*", "device_12"));
+        assert!(wildcard_match("*_invert_*", "prefix_invert_3_172"));
+        assert!(wildcard_match("device_?2", "device_12"));
+        assert!(!wildcard_match("device_?2", "device_2"));
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match("*a", "bbb"));
+    }
+}
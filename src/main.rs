@@ -1,7 +1,10 @@
-use config::{Config, GroupVersionKind};
+use config::{Config, GroupVersionKind, PlanMode};
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
-use std::{sync::Arc, thread::sleep};
 use stressing_registry::MetricRegistry;
+use tracing::{error, info, warn};
 
 use tokio::{
     select,
@@ -10,15 +13,28 @@ use tokio::{
     time::{self},
 };
 
-use metrics_util::MetricKindMask;
-
-use metrics_exporter_prometheus::PrometheusBuilder;
-use sys_info;
-
+mod compare;
 mod config;
+mod error;
+mod events;
+mod explain;
+mod exporter;
+mod inventory;
+mod logging;
+mod migrate;
+mod otlp;
+mod overrides;
+mod preprocess;
+mod push;
+mod render;
+mod report;
+mod schema;
+mod shutdown;
 mod stressing;
 mod stressing_registry;
+mod tui;
 mod util;
+mod validate;
 
 #[cfg(not(target_env = "msvc"))]
 use tikv_jemallocator::Jemalloc;
@@ -45,17 +61,57 @@ async fn main() {
                 .short(Some('c'))
                 .help("Max connections for the test"),
         )
+        .arg(clap::arg!(--"tui").help("Show a live dashboard instead of the per-connection output"))
+        .arg(
+            clap::arg!(--"max-breakdown-keys" <NUM>)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("100")
+                .help("Max brokers, tenants or info models broken down, the others are counted as `other`"),
+        )
+        .arg(
+            clap::arg!(--"report" <PATH>)
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .help("Write the JSON report of the run to the file"),
+        )
+        .args(logging::args())
+        .args(events::args())
+        .args(exporter::args())
+        .args(push::args())
+        .args(otlp::args())
+        .args(shutdown::args())
+        .args(overrides::args())
+        .args_conflicts_with_subcommands(true)
+        .subcommand(compare::command())
+        .subcommand(validate::command())
+        .subcommand(render::command())
+        .subcommand(explain::command())
+        .subcommand(schema::command())
+        .subcommand(migrate::command())
         .get_matches();
 
+    match matches.subcommand() {
+        Some(("compare", sub_matches)) => std::process::exit(compare::execute(sub_matches)),
+        Some(("validate", sub_matches)) => std::process::exit(validate::execute(sub_matches)),
+        Some(("render", sub_matches)) => std::process::exit(render::execute(sub_matches)),
+        Some(("explain", sub_matches)) => std::process::exit(explain::execute(sub_matches)),
+        Some(("schema", sub_matches)) => std::process::exit(schema::execute(sub_matches)),
+        Some(("migrate", sub_matches)) => std::process::exit(migrate::execute(sub_matches)),
+        _ => {}
+    }
+
+    let log_guard = logging::init(&logging::LogOptions::from_matches(&matches))
+        .expect("failed to initialize the logging");
+
+    let shutdown_options = shutdown::ShutdownOptions::from_matches(&matches);
+    let mut shutdown = shutdown::listen(&shutdown_options);
+
+    if let Some(path) = matches.get_one::<std::path::PathBuf>("event-log") {
+        events::install(path).expect("failed to open the event log");
+    }
+
     // Start prometheus exporter
-    let builder = PrometheusBuilder::new();
-    builder
-        .idle_timeout(
-            MetricKindMask::COUNTER | MetricKindMask::GAUGE,
-            Some(Duration::from_secs(10)),
-        )
-        .install()
-        .expect("failed to install Prometheus recorder");
+    let exporter_options = exporter::ExporterOptions::from_matches(&matches);
+    exporter::install(&exporter_options).expect("failed to install Prometheus recorder");
 
     let path = matches
         .get_one::<std::path::PathBuf>("file")
@@ -65,28 +121,282 @@ async fn main() {
         .get_one::<usize>("max-connections")
         .unwrap_or(&usize::MAX);
 
-    let file_path = path.as_os_str().to_str().expect("extract file path");
-    let spec =
-        config::Stressing::from_file(file_path).expect("config file should be a valid yaml file");
+    let mut tui = matches.get_flag("tui");
+    if tui && !std::io::stdout().is_terminal() {
+        warn!("stdout isn't a terminal, the dashboard is disabled");
+        tui = false;
+    }
 
-    let task_name = spec.meta().name;
-    let original = stressing_registry::MetricRegistry::new(task_name);
-    original.start_task();
-    let reg = Arc::new(original);
+    let config = match validate::read(path, &overrides::from_matches(&matches)) {
+        Ok(config) => config,
+        Err(_) => exit(2, log_guard).await,
+    };
+    let (plan_name, mut plan) = match config.parse() {
+        Ok(parsed) => parsed,
+        Err(issue) => {
+            error!(%issue, "invalid config");
+            exit(2, log_guard).await;
+        }
+    };
+    // The effective config of every spec is echoed in its report, without
+    // the credentials
+    let explained = explain::explain_plan(&config.contents, &plan, true);
+    let mut issues = validate::load(&mut plan, path.parent().unwrap_or(Path::new(".")));
+    issues.extend(validate::check(&plan));
+    if !issues.is_empty() {
+        for issue in &issues {
+            error!(%issue, "invalid config");
+        }
+        exit(2, log_guard).await;
+    }
+    if tui && plan.mode == PlanMode::Concurrent && plan.specs.len() > 1 {
+        warn!("the specs of the plan run concurrently, the dashboard is disabled");
+        tui = false;
+    }
+
+    // Every spec has its own registry, the spec name is its task name
+    let hostname = sys_info::hostname().unwrap();
+    let host_label = (String::from(stressing_registry::HOST), hostname.clone());
+    let max_breakdown_keys = *matches.get_one::<usize>("max-breakdown-keys").unwrap();
+    let registries: Vec<Arc<MetricRegistry>> = plan
+        .specs
+        .iter()
+        .map(|spec| {
+            let meta = spec.meta();
+            let mut labels = vec![host_label.clone()];
+            labels.extend(meta.metric_labels());
+            let registry = MetricRegistry::new(meta.name)
+                .with_labels(labels)
+                .with_max_breakdown_keys(max_breakdown_keys);
+            Arc::new(registry)
+        })
+        .collect();
     let my_client = Arc::new(util::MyClient::new());
-    let handles = match spec.spec {
-        config::Spec::Test(_) => panic!("unsupported spec"),
-        config::Spec::Publish(config) => {
-            start_publish_tasks(my_client, reg.clone(), config, max_connnection)
+
+    let push_options = push::PushOptions::from_matches(&matches);
+    let pusher = if push_options.enabled() {
+        let pusher = Arc::new(push::Pusher::new(
+            my_client.clone(),
+            registries.clone(),
+            push_options.clone(),
+            exporter_options.prefix.clone(),
+            hostname.clone(),
+        ));
+        push::start(pusher.clone());
+        Some(pusher)
+    } else {
+        None
+    };
+
+    let otlp_exporter = match matches.get_one::<String>("otlp-endpoint") {
+        Some(endpoint) => {
+            let tracer = if matches.get_flag("otlp-spans") {
+                Some(otlp::install_tracer())
+            } else {
+                None
+            };
+            let otlp_exporter = Arc::new(otlp::Exporter::new(
+                my_client.clone(),
+                registries.clone(),
+                tracer,
+                endpoint.clone(),
+                exporter_options.prefix.clone(),
+                hostname,
+            ));
+            otlp::start(otlp_exporter.clone(), &push_options);
+            Some(otlp_exporter)
         }
+        None => None,
     };
 
-    futures::future::join_all(handles).await;
-    reg.task_stopped();
+    let started_at = Instant::now();
+    let names: Vec<String> = plan
+        .specs
+        .iter()
+        .map(|spec| spec.meta().name.clone())
+        .collect();
+    let runs = plan.specs.into_iter().zip(registries.iter().cloned());
+    let mut thresholds = vec![];
+    let mut failed = false;
+    match plan.mode {
+        PlanMode::Concurrent => {
+            let runs = runs.map(|(spec, reg)| {
+                run_spec(
+                    spec,
+                    reg,
+                    my_client.clone(),
+                    max_connnection,
+                    tui,
+                    shutdown.clone(),
+                )
+            });
+            for (name, result) in names.iter().zip(futures::future::join_all(runs).await) {
+                thresholds.push(spec_thresholds(name, result, &mut failed));
+            }
+        }
+        PlanMode::Sequential => {
+            for (spec, reg) in runs {
+                if shutdown.is_stopped() {
+                    info!(spec = %spec.meta().name, "interrupted, the spec isn't started");
+                    thresholds.push(vec![]);
+                    continue;
+                }
+                let name = spec.meta().name.clone();
+                let run = run_spec(
+                    spec,
+                    reg,
+                    my_client.clone(),
+                    max_connnection,
+                    tui,
+                    shutdown.clone(),
+                );
+                thresholds.push(spec_thresholds(&name, run.await, &mut failed));
+            }
+        }
+    }
 
-    println!("Sleep 30 seconds before exiting...");
-    sleep(Duration::from_secs(30));
-    println!("All tasks run finished");
+    let mut reports = vec![];
+    let mut verdicts = vec![];
+    for ((reg, thresholds), explained) in registries.iter().zip(&thresholds).zip(explained) {
+        let mut report = report::Report::from_snapshot(&reg.snapshot());
+        report.config = explained;
+        print!("{}", report);
+        let spec_verdicts = report::evaluate(&report, thresholds);
+        if !spec_verdicts.is_empty() {
+            print!("{}", report::verdict_table(&spec_verdicts));
+        }
+        verdicts.extend(spec_verdicts);
+        reports.push(report);
+    }
+    // The report of a plan combines the reports of its specs
+    let report = if reports.len() == 1 {
+        reports.remove(0)
+    } else {
+        let combined = MetricRegistry::new(plan_name).with_labels(vec![host_label]);
+        for reg in &registries {
+            combined.absorb(reg);
+        }
+        let mut snapshot = combined.snapshot();
+        snapshot.elapsed = started_at.elapsed();
+        let mut report = report::Report::from_snapshot(&snapshot);
+        print!("{}", report);
+        report.specs = reports;
+        report
+    };
+    if let Some(path) = matches.get_one::<std::path::PathBuf>("report") {
+        if let Err(e) = report.write_file(path) {
+            error!(path = %path.display(), error = %e, "write report failed");
+        }
+    }
+
+    events::flush().await;
+
+    // Final push, the process may be gone before the next interval
+    if let Some(pusher) = &pusher {
+        pusher.push().await;
+    }
+    if let Some(otlp_exporter) = &otlp_exporter {
+        otlp_exporter.export().await;
+    }
+
+    // Keep the metrics endpoint up for the last scrape, unless interrupted
+    if !shutdown.is_stopped() && !shutdown_options.linger.is_zero() {
+        info!(
+            linger_secs = shutdown_options.linger.as_secs(),
+            "Lingering before exiting..."
+        );
+        select! {
+            _ = time::sleep(shutdown_options.linger) => {},
+            _ = shutdown.stopped() => {},
+        }
+    }
+    info!("All tasks run finished");
+    if verdicts.iter().any(|verdict| !verdict.passed) {
+        error!("Thresholds were violated");
+        exit(1, log_guard).await;
+    }
+    if failed {
+        exit(1, log_guard).await;
+    }
+    if shutdown.is_stopped() {
+        exit(shutdown::INTERRUPTED_EXIT_CODE, log_guard).await;
+    }
+}
+
+// exit writes the buffered events and logs before exiting, process::exit
+// skips the destructors
+async fn exit(code: i32, log_guard: tracing_appender::non_blocking::WorkerGuard) -> ! {
+    events::flush().await;
+    drop(log_guard);
+    std::process::exit(code);
+}
+
+// spec_thresholds returns the thresholds of a spec, a spec whose tasks
+// couldn't be started fails the run and has no thresholds
+fn spec_thresholds(
+    name: &str,
+    result: error::BenchResult<Vec<config::Threshold>>,
+    failed: &mut bool,
+) -> Vec<config::Threshold> {
+    match result {
+        Ok(thresholds) => thresholds,
+        Err(e) => {
+            error!(spec = %name, error = %e, "start the tasks of the spec failed");
+            *failed = true;
+            vec![]
+        }
+    }
+}
+
+// run_spec runs the tasks of a spec until all of them exited, it returns the
+// thresholds of the spec.
+async fn run_spec(
+    spec: config::Stressing,
+    reg: Arc<MetricRegistry>,
+    http_client: Arc<util::MyClient>,
+    max_connection: &usize,
+    tui: bool,
+    shutdown: shutdown::Shutdown,
+) -> error::BenchResult<Vec<config::Threshold>> {
+    let config = match spec.spec {
+        config::Spec::Publish(config) => config,
+        // The other kinds were rejected by the validation
+        _ => unreachable!("unsupported spec"),
+    };
+    let thresholds = config.thresholds.clone();
+    reg.start_task();
+    let mut dashboard = None;
+    if tui {
+        util::set_quiet(true);
+        let expected = stressing::expected_duration(&config);
+        dashboard = Some(tui::start(reg.clone(), expected));
+    }
+    // The heartbeat updates the exported metrics while the tasks run
+    let registry = reg.clone();
+    let heartbeat = tokio::spawn(async move {
+        let mut heartbeat = time::interval_at(Instant::now(), Duration::from_millis(1000));
+        loop {
+            heartbeat.tick().await;
+            registry.update();
+        }
+    });
+    let result =
+        match start_publish_tasks(http_client, reg.clone(), config, max_connection, shutdown) {
+            Ok(handles) => {
+                futures::future::join_all(handles).await;
+                Ok(thresholds)
+            }
+            Err(e) => Err(e),
+        };
+    heartbeat.abort();
+    reg.task_stopped();
+    // The exporters get the final counters and the stopped status
+    reg.update();
+    if let Some(dashboard) = dashboard {
+        let _ = dashboard.await;
+        util::set_quiet(false);
+    }
+    result
 }
 
 fn start_publish_tasks(
@@ -94,18 +404,20 @@ fn start_publish_tasks(
     reg: Arc<MetricRegistry>,
     config: Config,
     max_connection: &usize,
-) -> Vec<JoinHandle<()>> {
+    shutdown: shutdown::Shutdown,
+) -> error::BenchResult<Vec<JoinHandle<()>>> {
     let len = if config.things_info.len() < *max_connection {
         config.things_info.len()
     } else {
-        *max_connection as usize
+        *max_connection
     };
 
+    let tls = stressing::tls_connector(&config.tls)?;
     let mut handles = vec![];
+    reg.planned_tasks_add(len);
+    reg.target_publishes_add(len * stressing::publish_loops(&config).max(0) as usize);
     let arc_cfg = Arc::new(config);
 
-    let hostname = sys_info::hostname().unwrap();
-
     // Run tasks for the stressing test
     for i in 0..len {
         let cfg = arc_cfg.clone();
@@ -115,20 +427,9 @@ fn start_publish_tasks(
             reg.clone(),
             cfg,
             i,
+            tls.clone(),
+            shutdown.clone(),
         )))
     }
-
-    let registry = reg.clone();
-    let labels = [(String::from("host"), hostname)];
-    tokio::spawn(async move {
-        let mut heartbeat = time::interval_at(Instant::now(), Duration::from_millis(1000));
-        loop {
-            select! {
-                _ = heartbeat.tick() => {
-                    registry.update(&labels);
-                },
-            }
-        }
-    });
-    return handles;
+    Ok(handles)
 }
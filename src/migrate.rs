@@ -0,0 +1,326 @@
+use std::io::{Error, Result};
+
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+// Version of the config format
+pub const CURRENT_VERSION: &str = "v1.0.1";
+
+// Migration upgrades a publish spec of the version to the next one, it
+// returns the notes about the changes which need a review
+struct Migration {
+    from: &'static str,
+    to: &'static str,
+    apply: fn(&mut Mapping) -> Vec<String>,
+}
+
+// Migrations in order, the last one upgrades to the current version
+const MIGRATIONS: [Migration; 1] = [Migration {
+    from: "v1.0.0",
+    to: "v1.0.1",
+    apply: v1_0_0,
+}];
+
+// is_supported tells whether the version is the current one or can be
+// upgraded to it
+pub fn is_supported(version: &str) -> bool {
+    version == CURRENT_VERSION || MIGRATIONS.iter().any(|m| m.from == version)
+}
+
+pub fn supported_versions() -> String {
+    MIGRATIONS
+        .iter()
+        .map(|m| m.from)
+        .chain([CURRENT_VERSION])
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// migrate upgrades every document of the config and the specs of a plan to
+// the current version. The config is returned as is when it's current, so
+// the errors of its specs keep their line and column; otherwise it's
+// rewritten and its comments are lost. The unknown versions are kept, the
+// parser of the specs reports them.
+pub fn migrate(contents: &str) -> Result<(String, Vec<String>)> {
+    let mut documents = vec![];
+    let mut notes = vec![];
+    for document in serde_yaml::Deserializer::from_str(contents) {
+        match Value::deserialize(document) {
+            Ok(value) => documents.push(value),
+            // The syntax errors are reported with the location by the parser of the specs
+            Err(_) => return Ok((contents.to_string(), notes)),
+        }
+    }
+    for (idx, document) in documents.iter_mut().enumerate() {
+        migrate_document(document, &format!("document {}", idx + 1), &mut notes);
+    }
+    if notes.is_empty() {
+        return Ok((contents.to_string(), notes));
+    }
+    let mut migrated = vec![];
+    for document in &documents {
+        migrated.push(serde_yaml::to_string(document).map_err(Error::other)?);
+    }
+    Ok((migrated.join("---\n"), notes))
+}
+
+// migrate_document upgrades the document version by version, every applied
+// migration adds a note
+fn migrate_document(document: &mut Value, name: &str, notes: &mut Vec<String>) {
+    let Value::Mapping(mapping) = document else {
+        return;
+    };
+    let kind = kind(mapping).map(str::to_ascii_lowercase);
+    if kind.as_deref() == Some("plan") {
+        if let Some(Value::Sequence(specs)) = mapping
+            .get_mut("spec")
+            .and_then(|spec| spec.get_mut("specs"))
+        {
+            for (idx, spec) in specs.iter_mut().enumerate() {
+                migrate_document(spec, &format!("{} spec.specs[{}]", name, idx), notes);
+            }
+        }
+    }
+    let Some(mut version) = mapping
+        .get("version")
+        .and_then(Value::as_str)
+        .map(String::from)
+    else {
+        return;
+    };
+    let is_publish = kind.as_deref() == Some("publish");
+    while let Some(migration) = MIGRATIONS.iter().find(|m| m.from == version) {
+        let changes = match mapping.get_mut("spec") {
+            Some(Value::Mapping(spec)) if is_publish => (migration.apply)(spec),
+            _ => vec![],
+        };
+        notes.push(format!("{}: {} -> {}", name, migration.from, migration.to));
+        notes.extend(
+            changes
+                .into_iter()
+                .map(|change| format!("{}: {}", name, change)),
+        );
+        version = migration.to.to_string();
+        mapping.insert("version".into(), version.clone().into());
+    }
+}
+
+fn kind(mapping: &Mapping) -> Option<&str> {
+    mapping.get("kind").and_then(Value::as_str)
+}
+
+// v1_0_0 upgrades the single device layout. The device was in `data`, with
+// `infoModelId` rather than `infoModelName`, its plain text payload in
+// `payload` and `connection` devices were connected.
+fn v1_0_0(spec: &mut Mapping) -> Vec<String> {
+    let mut notes = vec![];
+    let connection = spec.remove("connection").and_then(|c| c.as_u64());
+    let payload = spec.remove("payload");
+    let data = match spec.remove("data") {
+        Some(Value::Mapping(data)) => data,
+        _ => Mapping::new(),
+    };
+    if let Some(Value::String(template)) = spec.get_mut("topicTemplate") {
+        *template = template.replace("${infoModelId}", "${infoModelName}");
+    }
+    if let Some(Value::Mapping(token)) = spec.get_mut("dynamicToken") {
+        if let Some(Value::String(payload)) = token.get_mut("payload") {
+            *payload = payload.replace("${infoModelId}", "${infoModelName}");
+        }
+    }
+
+    let mut device = Mapping::new();
+    let mut context = Mapping::new();
+    for (key, value) in data {
+        match key.as_str() {
+            Some("tenantName" | "thirdThingsId" | "password") => {
+                device.insert(key, value);
+            }
+            Some("infoModelId") => {
+                device.insert("infoModelName".into(), value);
+            }
+            _ => {
+                context.insert(key, value);
+            }
+        }
+    }
+    if !context.is_empty() {
+        device.insert("context".into(), Value::Mapping(context));
+    }
+    let tenant = device
+        .get("tenantName")
+        .and_then(Value::as_str)
+        .map(String::from);
+
+    if let Some(payload) = payload {
+        match &tenant {
+            Some(tenant) if !spec.contains_key("thingsPayloads") => {
+                let payloads = Mapping::from_iter([(tenant.as_str().into(), payload)]);
+                spec.insert("thingsPayloads".into(), Value::Mapping(payloads));
+                // The payload was published as is
+                if !spec.contains_key("isPayloadBase64") {
+                    spec.insert("isPayloadBase64".into(), false.into());
+                }
+            }
+            _ => notes.push(
+                "payload is dropped, set it in thingsPayloads by the tenant name".to_string(),
+            ),
+        }
+    }
+
+    if device.is_empty() || spec.contains_key("thingsInfo") {
+        if !device.is_empty() {
+            notes.push("data is dropped, the spec has thingsInfo".to_string());
+        }
+        return notes;
+    }
+    if let Some(count) = connection.filter(|count| *count > 1) {
+        // Every connection was a device, they're generated by a range
+        if let Some(Value::String(id)) = device.get_mut("thirdThingsId") {
+            if !id.contains("${index") {
+                id.push_str("_${index}");
+                notes.push(format!("connection {} generates the devices {}", count, id));
+            }
+        }
+        let range = Mapping::from_iter([("count".into(), count.into())]);
+        device.insert("range".into(), Value::Mapping(range));
+    }
+    spec.insert(
+        "thingsInfo".into(),
+        Value::Sequence(vec![Value::Mapping(device)]),
+    );
+    notes
+}
+
+pub fn command() -> clap::Command {
+    clap::Command::new("migrate")
+        .about("Upgrade a config file of an older version to the current one")
+        .arg(
+            clap::arg!(--"file" <PATH>)
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .short(Some('f'))
+                .required(true)
+                .help("Config file for stress test"),
+        )
+        .arg(
+            clap::arg!(--"output" <PATH>)
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .short(Some('o'))
+                .help("Write the upgraded config to the file instead of stdout, it may be the config file itself"),
+        )
+}
+
+// execute prints the upgraded config, the environment variables and the
+// includes are kept. The notes of the migrations and the errors go to
+// stderr, so stdout is the config.
+pub fn execute(matches: &clap::ArgMatches) -> i32 {
+    let path = matches.get_one::<std::path::PathBuf>("file").unwrap();
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("read config {} failed: {}", path.display(), e);
+            return 2;
+        }
+    };
+    let (migrated, notes) = match migrate(&contents) {
+        Ok(migrated) => migrated,
+        Err(e) => {
+            eprintln!("{}: config: {}", path.display(), e);
+            return 1;
+        }
+    };
+    if notes.is_empty() {
+        eprintln!("{} is already {}", path.display(), CURRENT_VERSION);
+    }
+    for note in &notes {
+        eprintln!("{}", note);
+    }
+    match matches.get_one::<std::path::PathBuf>("output") {
+        Some(output) => {
+            if let Err(e) = std::fs::write(output, migrated) {
+                eprintln!("write config {} failed: {}", output.display(), e);
+                return 2;
+            }
+            0
+        }
+        None => {
+            print!("{}", migrated);
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::migrate;
+    use crate::config::{specs_from_str, Plan};
+
+    #[test]
+    fn test_migrate_v1_0_0() {
+        let contents = r#"group: github.com/zhao-kun/mqtt-bench
+version: v1.0.0
+kind: publish
+metaData:
+  name: task-demo
+spec:
+  brokerAddr: ["127.0.0.1:1883"]
+  connection: 100
+  payload: "hello world"
+  topicTemplate: "/${tenantName}/${infoModelId}/${thirdThingsId}/raw"
+  data:
+    tenantName: google
+    infoModelId: demo_v1
+    thirdThingsId: thirdThingsID
+    site: north
+"#;
+        let (migrated, notes) = migrate(contents).unwrap();
+        assert_eq!(
+            migrated,
+            r#"group: github.com/zhao-kun/mqtt-bench
+version: v1.0.1
+kind: publish
+metaData:
+  name: task-demo
+spec:
+  brokerAddr:
+  - 127.0.0.1:1883
+  topicTemplate: /${tenantName}/${infoModelName}/${thirdThingsId}/raw
+  thingsPayloads:
+    google: hello world
+  isPayloadBase64: false
+  thingsInfo:
+  - tenantName: google
+    infoModelName: demo_v1
+    thirdThingsId: thirdThingsID_${index}
+    context:
+      site: north
+    range:
+      count: 100
+"#
+        );
+        assert_eq!(
+            notes,
+            vec![
+                "document 1: v1.0.0 -> v1.0.1",
+                "document 1: connection 100 generates the devices thirdThingsID_${index}",
+            ]
+        );
+        let (_, plan) = Plan::from_documents(specs_from_str(&migrated).unwrap()).unwrap();
+        assert_eq!(plan.specs.len(), 1);
+
+        // A current config is kept as is
+        let (current, notes) = migrate(&migrated).unwrap();
+        assert_eq!(current, migrated);
+        assert!(notes.is_empty());
+
+        // The specs of a plan are migrated
+        let plan = format!(
+            "version: v1.0.1\nkind: plan\nmetaData:\n  name: plan\nspec:\n  specs:\n  - {}",
+            contents.trim_end().replace('\n', "\n    ")
+        );
+        let (migrated, notes) = migrate(&plan).unwrap();
+        assert_eq!(notes[0], "document 1 spec.specs[0]: v1.0.0 -> v1.0.1");
+        assert!(migrated.contains("    version: v1.0.1\n"));
+        assert!(migrated.contains("    thingsInfo:\n"));
+    }
+}
@@ -0,0 +1,513 @@
+use std::io::{Error, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use serde_json::{json, Value};
+use tokio::{task::JoinHandle, time};
+
+use crate::config::ThingsInfo;
+use crate::push::PushOptions;
+use crate::stressing_registry::{MetricKind, MetricRegistry};
+use crate::util::MyClient;
+
+const SERVICE_NAME: &str = "mqtt-bench";
+
+// OTLP status codes of a span
+const STATUS_OK: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+
+// SPAN_KIND_CLIENT, every span is an outgoing call to the token server or the broker
+const SPAN_KIND_CLIENT: u8 = 3;
+
+// TRACER is installed when the connection lifecycle spans are enabled
+static TRACER: OnceLock<Arc<Tracer>> = OnceLock::new();
+
+pub fn args() -> Vec<clap::Arg> {
+    vec![
+        clap::arg!(--"otlp-endpoint" <URL>)
+            .help("Export the metrics to the OTLP/HTTP endpoint, e.g. http://127.0.0.1:4318"),
+        clap::arg!(--"otlp-spans")
+            .requires("otlp-endpoint")
+            .help("Export the connection lifecycle spans to the OTLP endpoint"),
+    ]
+}
+
+#[derive(Debug, Clone)]
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: String,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+// Max spans buffered between two exports, every PUBACK is a span, the buffer
+// would grow without limit when the endpoint is down or slow
+const MAX_BUFFERED_SPANS: usize = 100_000;
+
+// Tracer buffers the finished spans until the next export, the spans beyond
+// the limit are dropped and counted
+#[derive(Debug)]
+pub struct Tracer {
+    spans: Mutex<Vec<SpanData>>,
+    max_spans: usize,
+    dropped: AtomicUsize,
+}
+
+impl Default for Tracer {
+    fn default() -> Tracer {
+        Tracer::with_max_spans(MAX_BUFFERED_SPANS)
+    }
+}
+
+impl Tracer {
+    fn with_max_spans(max_spans: usize) -> Tracer {
+        Tracer {
+            spans: Mutex::new(vec![]),
+            max_spans,
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    fn record(&self, span: SpanData) {
+        let mut spans = self.spans.lock().unwrap();
+        if spans.len() >= self.max_spans {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        spans.push(span);
+    }
+
+    // drain returns the buffered spans and the number of the spans dropped
+    // since the last drain
+    fn drain(&self) -> (Vec<SpanData>, usize) {
+        let spans = std::mem::take(&mut *self.spans.lock().unwrap());
+        (spans, self.dropped.swap(0, Ordering::Relaxed))
+    }
+}
+
+pub fn install_tracer() -> Arc<Tracer> {
+    TRACER.get_or_init(|| Arc::new(Tracer::default())).clone()
+}
+
+// ConnectionTrace records the lifecycle spans of a single connection, all
+// spans are children of a `connection` span. It does nothing when the
+// tracer isn't installed.
+pub struct ConnectionTrace {
+    tracer: Option<Arc<Tracer>>,
+    trace_id: String,
+    span_id: String,
+    start: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+}
+
+impl ConnectionTrace {
+    pub fn new(client_id: &str, things: &ThingsInfo) -> ConnectionTrace {
+        ConnectionTrace::with_tracer(TRACER.get().cloned(), client_id, things)
+    }
+
+    fn with_tracer(
+        tracer: Option<Arc<Tracer>>,
+        client_id: &str,
+        things: &ThingsInfo,
+    ) -> ConnectionTrace {
+        let (trace_id, span_id, attributes) = match tracer {
+            Some(_) => (
+                random_id::<16>(),
+                random_id::<8>(),
+                vec![
+                    ("mqtt.client_id", client_id.to_string()),
+                    ("things.id", things.third_things_id.clone()),
+                    ("things.tenant", things.tenant_name.clone()),
+                    ("things.info_model", things.info_model_name.clone()),
+                ],
+            ),
+            None => (String::new(), String::new(), vec![]),
+        };
+        ConnectionTrace {
+            tracer,
+            trace_id,
+            span_id,
+            start: SystemTime::now(),
+            attributes,
+        }
+    }
+
+    pub fn set_broker(&mut self, broker: &str) {
+        if self.tracer.is_some() {
+            self.attributes.push(("mqtt.broker", broker.to_string()));
+        }
+    }
+
+    // span records a child span which lasted `elapsed` and ends now, the
+    // elapsed time is measured by a monotonic clock
+    pub fn span(&self, name: &'static str, elapsed: Duration, error: Option<String>) {
+        if let Some(tracer) = &self.tracer {
+            let end = SystemTime::now();
+            tracer.record(SpanData {
+                trace_id: self.trace_id.clone(),
+                span_id: random_id::<8>(),
+                parent_span_id: self.span_id.clone(),
+                name,
+                start: end.checked_sub(elapsed).unwrap_or(end),
+                end,
+                attributes: self.attributes.clone(),
+                error,
+            });
+        }
+    }
+
+    // finish records the `connection` span covering the whole lifecycle
+    pub fn finish(self, error: Option<String>) {
+        if let Some(tracer) = &self.tracer {
+            tracer.record(SpanData {
+                trace_id: self.trace_id,
+                span_id: self.span_id,
+                parent_span_id: String::new(),
+                name: "connection",
+                start: self.start,
+                end: SystemTime::now(),
+                attributes: self.attributes,
+                error,
+            });
+        }
+    }
+}
+
+fn random_id<const N: usize>() -> String {
+    let mut bytes = [0u8; N];
+    rand::thread_rng().fill(&mut bytes[..]);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn attributes<K: AsRef<str>>(attributes: &[(K, String)]) -> Value {
+    attributes
+        .iter()
+        .map(|(k, v)| json!({"key": k.as_ref(), "value": {"stringValue": v}}))
+        .collect()
+}
+
+// Exporter exports the snapshots of the registries, one per spec, and the buffered spans to an
+// OTLP/HTTP endpoint using the JSON encoding.
+pub struct Exporter {
+    http_client: Arc<MyClient>,
+    registries: Vec<Arc<MetricRegistry>>,
+    tracer: Option<Arc<Tracer>>,
+    endpoint: String,
+    prefix: Option<String>,
+    host: String,
+    start: SystemTime,
+}
+
+impl Exporter {
+    pub fn new(
+        http_client: Arc<MyClient>,
+        registries: Vec<Arc<MetricRegistry>>,
+        tracer: Option<Arc<Tracer>>,
+        endpoint: String,
+        prefix: Option<String>,
+        host: String,
+    ) -> Exporter {
+        Exporter {
+            http_client,
+            registries,
+            tracer,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            prefix,
+            host,
+            start: SystemTime::now(),
+        }
+    }
+
+    // export sends the metrics and the spans recorded since the last export,
+    // failures are reported but don't stop the test.
+    pub async fn export(&self) {
+        if let Err(e) = self.post("/v1/metrics", self.metrics()).await {
+            tracing::warn!(endpoint = %self.endpoint, error = %e, "export metrics failed");
+        }
+        if let Some(tracer) = &self.tracer {
+            let (spans, dropped) = tracer.drain();
+            if dropped > 0 {
+                tracing::warn!(
+                    endpoint = %self.endpoint,
+                    dropped,
+                    "spans were dropped, the buffer was full"
+                );
+            }
+            if spans.is_empty() {
+                return;
+            }
+            if let Err(e) = self.post("/v1/traces", self.traces(&spans)).await {
+                tracing::warn!(endpoint = %self.endpoint, error = %e, "export spans failed");
+            }
+        }
+    }
+
+    async fn post(&self, path: &str, body: Value) -> Result<()> {
+        let response = self
+            .http_client
+            .client
+            .post(format!("{}{}", self.endpoint, path))
+            .json(&body)
+            .send()
+            .await
+            .map_err(Error::other)?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::other(format!("status {}", response.status())))
+        }
+    }
+
+    fn resource(&self) -> Value {
+        json!({
+            "attributes": attributes(&[
+                ("service.name", SERVICE_NAME.to_string()),
+                ("host.name", self.host.clone()),
+            ])
+        })
+    }
+
+    fn metric_name(&self, name: &str) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{}_{}", prefix, name),
+            None => name.to_string(),
+        }
+    }
+
+    fn metrics(&self) -> Value {
+        let now = unix_nanos(SystemTime::now());
+        let start = unix_nanos(self.start);
+        let mut metrics = vec![];
+        for registry in &self.registries {
+            let registry_labels = registry.labels();
+            let data_point = |value: f64, sample_labels: &[(String, String)]| {
+                json!({
+                    "attributes": attributes(&[&registry_labels[..], sample_labels].concat()),
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": now,
+                    "asDouble": value,
+                })
+            };
+            let registry_metrics: Vec<Value> = registry
+                .samples()
+                .into_iter()
+                .map(|sample| match sample.kind {
+                    MetricKind::Counter => json!({
+                        "name": self.metric_name(&sample.name),
+                        "sum": {
+                            "dataPoints": [data_point(sample.value, &sample.labels)],
+                            // AGGREGATION_TEMPORALITY_CUMULATIVE
+                            "aggregationTemporality": 2,
+                            "isMonotonic": true,
+                        }
+                    }),
+                    MetricKind::Gauge => json!({
+                        "name": self.metric_name(&sample.name),
+                        "gauge": {"dataPoints": [data_point(sample.value, &sample.labels)]}
+                    }),
+                })
+                .collect();
+            metrics.extend(registry_metrics);
+
+            for summary in registry.summaries() {
+                let latency = summary.latency;
+                metrics.push(json!({
+                    "name": self.metric_name(summary.name),
+                    "unit": "s",
+                    "summary": {
+                        "dataPoints": [{
+                            "attributes": attributes(&[&registry_labels[..], &summary.labels[..]].concat()),
+                            "startTimeUnixNano": start,
+                            "timeUnixNano": now,
+                            "count": latency.count.to_string(),
+                            "sum": latency.mean * latency.count as f64 / 1000.0,
+                            "quantileValues": [
+                                {"quantile": 0.5, "value": latency.p50 / 1000.0},
+                                {"quantile": 0.9, "value": latency.p90 / 1000.0},
+                                {"quantile": 0.99, "value": latency.p99 / 1000.0},
+                                {"quantile": 1.0, "value": latency.max / 1000.0},
+                            ],
+                        }]
+                    }
+                }));
+            }
+        }
+
+        json!({
+            "resourceMetrics": [{
+                "resource": self.resource(),
+                "scopeMetrics": [{
+                    "scope": {"name": SERVICE_NAME},
+                    "metrics": metrics,
+                }]
+            }]
+        })
+    }
+
+    fn traces(&self, spans: &[SpanData]) -> Value {
+        let spans: Vec<Value> = spans
+            .iter()
+            .map(|span| {
+                let status = match &span.error {
+                    Some(message) => json!({"code": STATUS_ERROR, "message": message}),
+                    None => json!({"code": STATUS_OK}),
+                };
+                json!({
+                    "traceId": span.trace_id,
+                    "spanId": span.span_id,
+                    "parentSpanId": span.parent_span_id,
+                    "name": span.name,
+                    "kind": SPAN_KIND_CLIENT,
+                    "startTimeUnixNano": unix_nanos(span.start),
+                    "endTimeUnixNano": unix_nanos(span.end),
+                    "attributes": attributes(&span.attributes),
+                    "status": status,
+                })
+            })
+            .collect();
+        json!({
+            "resourceSpans": [{
+                "resource": self.resource(),
+                "scopeSpans": [{
+                    "scope": {"name": SERVICE_NAME},
+                    "spans": spans,
+                }]
+            }]
+        })
+    }
+}
+
+// start spawns a task exporting to the OTLP endpoint every push interval
+pub fn start(exporter: Arc<Exporter>, options: &PushOptions) -> JoinHandle<()> {
+    let mut interval = time::interval(options.interval);
+    tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            exporter.export().await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use serde_json::Value;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::{ConnectionTrace, Exporter, Tracer};
+    use crate::config::ThingsInfo;
+    use crate::stressing_registry::MetricRegistry;
+    use crate::util::MyClient;
+
+    #[tokio::test]
+    async fn test_export_metrics_and_spans() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/metrics"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/traces"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let reg = Arc::new(MetricRegistry::new("task-demo".to_string()));
+        reg.sent_packets_inc();
+        let tracer = Arc::new(Tracer::default());
+        let things = ThingsInfo {
+            tenant_name: "google".to_string(),
+            info_model_name: "demo_v1".to_string(),
+            third_things_id: "device_1".to_string(),
+            password: "password".to_string(),
+            ..Default::default()
+        };
+        let mut trace = ConnectionTrace::with_tracer(Some(tracer.clone()), "client_1", &things);
+        trace.span("token_fetch", Duration::from_millis(20), None);
+        trace.set_broker("127.0.0.1:1883");
+        trace.span(
+            "tcp_connect",
+            Duration::from_millis(5),
+            Some("refused".to_string()),
+        );
+        trace.finish(Some("refused".to_string()));
+
+        let exporter = Exporter::new(
+            Arc::new(MyClient::new()),
+            vec![reg],
+            Some(tracer.clone()),
+            server.uri(),
+            None,
+            "bench-0".to_string(),
+        );
+        exporter.export().await;
+        assert!(tracer.drain().0.is_empty());
+
+        let requests = server.received_requests().await.unwrap();
+        let metrics: Value = serde_json::from_slice(&requests[0].body).unwrap();
+        let names: Vec<&str> = metrics["resourceMetrics"][0]["scopeMetrics"][0]["metrics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["name"].as_str().unwrap())
+            .collect();
+        assert!(names.contains(&"sent_packets"));
+        assert!(names.contains(&"ack_latency_seconds"));
+
+        let traces: Value = serde_json::from_slice(&requests[1].body).unwrap();
+        let spans = traces["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        assert_eq!(spans.len(), 3);
+        let root = &spans[2];
+        assert_eq!(root["name"], "connection");
+        assert_eq!(root["status"]["code"], 2);
+        assert_eq!(spans[0]["parentSpanId"], root["spanId"]);
+        assert_eq!(spans[0]["traceId"], root["traceId"]);
+        let attributes = root["attributes"].to_string();
+        assert!(attributes.contains("device_1"));
+        assert!(attributes.contains("127.0.0.1:1883"));
+    }
+
+    #[test]
+    fn test_disabled_trace() {
+        let trace = ConnectionTrace::with_tracer(None, "client_1", &ThingsInfo::default());
+        trace.span("token_fetch", Duration::from_millis(20), None);
+        assert!(trace.trace_id.is_empty());
+        trace.finish(None);
+    }
+
+    #[test]
+    fn test_tracer_drops_spans_beyond_the_limit() {
+        let tracer = Arc::new(Tracer::with_max_spans(2));
+        let things = ThingsInfo::default();
+        let trace = ConnectionTrace::with_tracer(Some(tracer.clone()), "client_1", &things);
+        for _ in 0..3 {
+            trace.span("publish", Duration::from_millis(1), None);
+        }
+        trace.finish(None);
+        let (spans, dropped) = tracer.drain();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(dropped, 2);
+        assert_eq!(tracer.drain().1, 0);
+    }
+}
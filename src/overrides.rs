@@ -0,0 +1,168 @@
+use std::io::{Error, Result};
+
+use serde::Deserialize;
+use serde_yaml::Value;
+
+pub fn args() -> Vec<clap::Arg> {
+    vec![clap::arg!(--"set" <OVERRIDE>)
+        .value_parser(Override::parse)
+        .action(clap::ArgAction::Append)
+        .help("Override a field of the config, e.g. spec.thinkTime=1000 or spec.brokerAddr[0]=10.0.0.5:1883, repeatable")]
+}
+
+pub fn from_matches(matches: &clap::ArgMatches) -> Vec<Override> {
+    matches
+        .get_many::<Override>("set")
+        .unwrap_or_default()
+        .cloned()
+        .collect()
+}
+
+// Override sets the field of the path to the value, the value is parsed as
+// YAML, e.g. `1000` is a number and `[a, b]` a sequence
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    // the path as given, for the errors
+    raw: String,
+    path: Vec<Segment>,
+    value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl Override {
+    pub fn parse(s: &str) -> std::result::Result<Override, String> {
+        let (raw, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("{} should be PATH=VALUE", s))?;
+        let mut path = vec![];
+        for part in raw.split('.') {
+            let (key, indexes) = part.split_once('[').unwrap_or((part, ""));
+            if key.is_empty() {
+                return Err(format!("{} has an empty key", raw));
+            }
+            path.push(Segment::Key(key.to_string()));
+            if indexes.is_empty() {
+                continue;
+            }
+            for index in indexes.trim_end_matches(']').split("][") {
+                let index = index
+                    .parse()
+                    .map_err(|_| format!("{} has an invalid index [{}]", raw, index))?;
+                path.push(Segment::Index(index));
+            }
+        }
+        // An empty value is an empty string rather than null
+        let value = match value {
+            "" => Value::String(String::new()),
+            value => serde_yaml::from_str(value).unwrap_or_else(|_| Value::String(value.into())),
+        };
+        Ok(Override {
+            raw: raw.to_string(),
+            path,
+            value,
+        })
+    }
+}
+
+// apply sets the overrides in every document of the config. The config is
+// returned as is without overrides, otherwise it's rewritten and the lines
+// of its errors don't match the file anymore.
+pub fn apply(contents: &str, overrides: &[Override]) -> Result<String> {
+    if overrides.is_empty() {
+        return Ok(contents.to_string());
+    }
+    let mut documents = vec![];
+    for document in serde_yaml::Deserializer::from_str(contents) {
+        let mut value = Value::deserialize(document).map_err(Error::other)?;
+        for o in overrides {
+            set(&mut value, &o.path, o.value.clone())
+                .map_err(|e| Error::other(format!("--set {}: {}", o.raw, e)))?;
+        }
+        documents.push(serde_yaml::to_string(&value).map_err(Error::other)?);
+    }
+    Ok(documents.join("---\n"))
+}
+
+// set creates the missing mappings and sequences of the path, a sequence may
+// be extended by its next index only
+fn set(target: &mut Value, path: &[Segment], value: Value) -> std::result::Result<(), String> {
+    let Some((segment, rest)) = path.split_first() else {
+        *target = value;
+        return Ok(());
+    };
+    match segment {
+        Segment::Key(key) => {
+            if target.is_null() {
+                *target = Value::Mapping(serde_yaml::Mapping::new());
+            }
+            let Value::Mapping(mapping) = target else {
+                return Err(format!("{} isn't in a mapping", key));
+            };
+            let child = mapping
+                .entry(Value::String(key.clone()))
+                .or_insert(Value::Null);
+            set(child, rest, value)
+        }
+        Segment::Index(index) => {
+            if target.is_null() {
+                *target = Value::Sequence(vec![]);
+            }
+            let Value::Sequence(sequence) = target else {
+                return Err(format!("[{}] isn't in a sequence", index));
+            };
+            if *index == sequence.len() {
+                sequence.push(Value::Null);
+            }
+            let len = sequence.len();
+            let child = sequence
+                .get_mut(*index)
+                .ok_or_else(|| format!("index {} is out of the {} items", index, len))?;
+            set(child, rest, value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, Override};
+
+    #[test]
+    fn test_apply_overrides() {
+        let contents = "kind: publish\nspec:\n  brokerAddr: [\"127.0.0.1:1883\"]\n  thingsInfo:\n  - tenantName: google\n";
+        let overrides: Vec<Override> = [
+            "spec.thinkTime=1000",
+            "spec.brokerAddr[0]=10.0.0.5:1883",
+            "spec.brokerAddr[1]=10.0.0.6:1883",
+            "spec.thingsInfo[0].context.site=north",
+            "spec.randomClientId=true",
+            "spec.password=",
+        ]
+        .iter()
+        .map(|s| Override::parse(s).unwrap())
+        .collect();
+        assert_eq!(
+            apply(contents, &overrides).unwrap(),
+            "kind: publish\nspec:\n  brokerAddr:\n  - 10.0.0.5:1883\n  - 10.0.0.6:1883\n  thingsInfo:\n  - tenantName: google\n    context:\n      site: north\n  thinkTime: 1000\n  randomClientId: true\n  password: ''\n"
+        );
+        assert_eq!(apply(contents, &[]).unwrap(), contents);
+
+        let e = apply(
+            contents,
+            &[Override::parse("spec.brokerAddr[3]=x").unwrap()],
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "--set spec.brokerAddr[3]: index 3 is out of the 1 items"
+        );
+        let e = apply(contents, &[Override::parse("kind.name=x").unwrap()]).unwrap_err();
+        assert_eq!(e.to_string(), "--set kind.name: name isn't in a mapping");
+        assert!(Override::parse("spec.thinkTime").is_err());
+        assert!(Override::parse("spec.brokerAddr[x]=1").is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use serde_yaml::Value;
+
+// Tag and key which include another YAML file, e.g. `thingsInfo: !include
+// devices.yml` or `thingsInfo: {$ref: devices.yml}`
+const INCLUDE_TAG: &str = "include";
+const REF_KEY: &str = "$ref";
+
+// Max depth of nested includes, guards against include cycles through links
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// preprocess substitutes the environment variables of the config and
+// resolves its includes relative to the directory of the config file. A
+// config with neither is returned as is; otherwise it's rewritten, its
+// comments are lost and the lines and columns of its errors don't match the
+// file anymore.
+pub fn preprocess(contents: &str, dir: &Path) -> Result<String> {
+    preprocess_with(contents, dir, &|name| std::env::var(name).ok())
+}
+
+fn preprocess_with(
+    contents: &str,
+    dir: &Path,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<String> {
+    let mut documents = vec![];
+    let mut changed = false;
+    for document in serde_yaml::Deserializer::from_str(contents) {
+        let value = match Value::deserialize(document) {
+            Ok(value) => value,
+            // The syntax errors are reported with the location by the parser of the specs
+            Err(_) => return Ok(contents.to_string()),
+        };
+        let mut includes = Includes {
+            env,
+            quoted: quoted_placeholders(contents),
+            stack: vec![],
+            changed: false,
+        };
+        documents.push(includes.resolve(value, dir)?);
+        changed |= includes.changed;
+    }
+    if !changed {
+        return Ok(contents.to_string());
+    }
+    let documents = documents
+        .iter()
+        .map(|document| serde_yaml::to_string(document).map_err(Error::other))
+        .collect::<Result<Vec<String>>>()?;
+    Ok(documents.join("---\n"))
+}
+
+const ENV_PREFIX: &str = "${env:";
+
+// substitute_env replaces `${env:VAR}` by the value of the environment
+// variable, `${env:VAR:-default}` falls back to the default if VAR isn't set
+// or is empty. The other placeholders, e.g. `${tenantName}`, are kept for the
+// templates.
+fn substitute_env(contents: &str, env: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::with_capacity(contents.len());
+    let mut rest = contents;
+    while let Some(start) = rest.find(ENV_PREFIX) {
+        out.push_str(&rest[..start]);
+        let placeholder = &rest[start + ENV_PREFIX.len()..];
+        let end = placeholder
+            .find('}')
+            .ok_or_else(|| Error::other(format!("unclosed {}{}", ENV_PREFIX, placeholder)))?;
+        let (name, default) = match placeholder[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&placeholder[..end], None),
+        };
+        let value = match (env(name).filter(|value| !value.is_empty()), default) {
+            (Some(value), _) => value,
+            (None, Some(default)) => default.to_string(),
+            (None, None) => {
+                return Err(Error::other(format!(
+                    "environment variable {} isn't set",
+                    name
+                )))
+            }
+        };
+        out.push_str(&value);
+        rest = &placeholder[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// quoted_placeholders returns the placeholders which are a quoted scalar of
+// the file, e.g. `"${env:PASSWORD}"`, their values stay strings
+fn quoted_placeholders(contents: &str) -> HashSet<String> {
+    let mut quoted = HashSet::new();
+    for quote in ['"', '\''] {
+        for (start, _) in contents.match_indices(&format!("{}{}", quote, ENV_PREFIX)) {
+            let placeholder = &contents[start + 1..];
+            if let Some(end) = placeholder.find('}') {
+                if placeholder[end + 1..].starts_with(quote) {
+                    quoted.insert(placeholder[..=end].to_string());
+                }
+            }
+        }
+    }
+    quoted
+}
+
+// Includes substitutes the environment variables of the scalars and resolves
+// the includes of a document. The variables are substituted after parsing, so
+// the comments are skipped and a value with `: ` or `#` stays a single scalar.
+struct Includes<'a> {
+    env: &'a dyn Fn(&str) -> Option<String>,
+    // placeholders quoted in the config or the included files
+    quoted: HashSet<String>,
+    // files being included, to report the include cycles
+    stack: Vec<PathBuf>,
+    // whether a variable was substituted or a file included
+    changed: bool,
+}
+
+impl Includes<'_> {
+    fn resolve(&mut self, value: Value, dir: &Path) -> Result<Value> {
+        if let Some(reference) = include_reference(&value) {
+            let reference = substitute_env(reference, self.env)?;
+            return self.include(&reference, dir);
+        }
+        match value {
+            Value::String(scalar) => self.substitute(scalar),
+            Value::Mapping(mapping) => {
+                let mut resolved = serde_yaml::Mapping::new();
+                for (key, value) in mapping {
+                    let key = match key {
+                        Value::String(key) => self.substitute(key)?,
+                        key => key,
+                    };
+                    resolved.insert(key, self.resolve(value, dir)?);
+                }
+                Ok(Value::Mapping(resolved))
+            }
+            Value::Sequence(sequence) => {
+                let mut resolved = vec![];
+                for value in sequence {
+                    // An included sequence is spliced into the sequence
+                    let splice = include_reference(&value).is_some();
+                    match self.resolve(value, dir)? {
+                        Value::Sequence(items) if splice => resolved.extend(items),
+                        value => resolved.push(value),
+                    }
+                }
+                Ok(Value::Sequence(resolved))
+            }
+            value => Ok(value),
+        }
+    }
+
+    // substitute replaces the variables of the scalar. A scalar which is a
+    // single unquoted variable is parsed like a plain scalar, so a number or
+    // a boolean keeps its type.
+    fn substitute(&mut self, scalar: String) -> Result<Value> {
+        if !scalar.contains(ENV_PREFIX) {
+            return Ok(Value::String(scalar));
+        }
+        self.changed = true;
+        let value = substitute_env(&scalar, self.env)?;
+        let single = scalar.starts_with(ENV_PREFIX) && scalar.find('}') == Some(scalar.len() - 1);
+        if single && !self.quoted.contains(&scalar) {
+            if let Ok(typed @ (Value::Number(_) | Value::Bool(_))) = serde_yaml::from_str(&value) {
+                return Ok(typed);
+            }
+        }
+        Ok(Value::String(value))
+    }
+
+    // include loads the file of the reference, `file.yml#/a/b` selects the
+    // value at the path in the file
+    fn include(&mut self, reference: &str, dir: &Path) -> Result<Value> {
+        let (file, pointer) = match reference.split_once('#') {
+            Some((file, pointer)) => (file, Some(pointer)),
+            None => (reference, None),
+        };
+        let path = dir.join(file);
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| Error::other(format!("include {} failed: {}", path.display(), e)))?;
+        if self.stack.contains(&canonical) || self.stack.len() >= MAX_INCLUDE_DEPTH {
+            return Err(Error::other(format!(
+                "include {} failed: includes itself",
+                path.display()
+            )));
+        }
+        let contents = fs::read_to_string(&canonical)
+            .map_err(|e| Error::other(format!("include {} failed: {}", path.display(), e)))?;
+        self.quoted.extend(quoted_placeholders(&contents));
+        let value: Value = serde_yaml::from_str(&contents)
+            .map_err(|e| Error::other(format!("include {} failed: {}", path.display(), e)))?;
+        let value = match pointer {
+            Some(pointer) => select(value, pointer).ok_or_else(|| {
+                Error::other(format!("include {} failed: no {}", path.display(), pointer))
+            })?,
+            None => value,
+        };
+
+        self.changed = true;
+        self.stack.push(canonical);
+        let included_dir = path.parent().unwrap_or(dir).to_path_buf();
+        let resolved = self.resolve(value, &included_dir);
+        self.stack.pop();
+        resolved
+    }
+}
+
+// include_reference returns the file of `!include file` or `{$ref: file}`
+fn include_reference(value: &Value) -> Option<&str> {
+    match value {
+        Value::Tagged(tagged) if tagged.tag == INCLUDE_TAG => tagged.value.as_str(),
+        Value::Mapping(mapping) if mapping.len() == 1 => mapping.get(REF_KEY)?.as_str(),
+        _ => None,
+    }
+}
+
+// select returns the value at the path, e.g. `/payloads/google` or `/0`
+fn select(value: Value, pointer: &str) -> Option<Value> {
+    let mut value = value;
+    for key in pointer.split('/').filter(|key| !key.is_empty()) {
+        value = match value {
+            Value::Sequence(mut sequence) => {
+                let idx: usize = key.parse().ok()?;
+                (idx < sequence.len()).then(|| sequence.swap_remove(idx))?
+            }
+            Value::Mapping(mut mapping) => mapping.remove(key)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{preprocess_with, substitute_env};
+
+    fn env(name: &str) -> Option<String> {
+        match name {
+            "BROKER" => Some("10.0.0.5:1883".to_string()),
+            "EMPTY" => Some(String::new()),
+            "TOKEN" => Some("a: b #c".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_substitute_env() {
+        let contents = "brokerAddr: [\"${env:BROKER}\"]\npassword: ${env:PASSWORD:-admin}\ntopicTemplate: /${tenantName}/${env:EMPTY:-raw}";
+        assert_eq!(
+            substitute_env(contents, &env).unwrap(),
+            "brokerAddr: [\"10.0.0.5:1883\"]\npassword: admin\ntopicTemplate: /${tenantName}/raw"
+        );
+        let e = substitute_env("password: ${env:PASSWORD}", &env).unwrap_err();
+        assert_eq!(e.to_string(), "environment variable PASSWORD isn't set");
+        assert!(substitute_env("password: ${env:PASSWORD", &env).is_err());
+    }
+
+    #[test]
+    fn test_preprocess_env() {
+        let dir = std::env::temp_dir();
+        let contents = "# password: ${env:PASSWORD}\nspec:\n  brokerAddr: [\"${env:BROKER}\"]\n  password: ${env:TOKEN} # ${env:PASSWORD}\n  duration: ${env:DURATION:-60}\n  clientId: '${env:CLIENT_ID:-60}'\n  topicTemplate: /${tenantName}/${env:EMPTY:-raw}\n";
+        assert_eq!(
+            preprocess_with(contents, &dir, &env).unwrap(),
+            "spec:\n  brokerAddr:\n  - 10.0.0.5:1883\n  password: 'a: b #c'\n  duration: 60\n  clientId: '60'\n  topicTemplate: /${tenantName}/raw\n"
+        );
+        let e = preprocess_with("password: ${env:PASSWORD}\n", &dir, &env).unwrap_err();
+        assert_eq!(e.to_string(), "environment variable PASSWORD isn't set");
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = std::env::temp_dir().join(format!("mqtt-bench-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("shared")).unwrap();
+        fs::write(
+            dir.join("shared/devices.yml"),
+            "- tenantName: google\n  thirdThingsId: device_1\n- !include more.yml\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("shared/more.yml"),
+            "tenantName: amazon\nthirdThingsId: ${env:BROKER}\n",
+        )
+        .unwrap();
+        fs::write(dir.join("payloads.yml"), "payloads:\n  google: aGVsbG8=\n").unwrap();
+
+        let contents = "spec:\n  thingsPayloads: {$ref: 'payloads.yml#/payloads'}\n  thingsInfo: !include shared/devices.yml\n";
+        let resolved = preprocess_with(contents, &dir, &env).unwrap();
+        assert_eq!(
+            resolved,
+            "spec:\n  thingsPayloads:\n    google: aGVsbG8=\n  thingsInfo:\n  - tenantName: google\n    thirdThingsId: device_1\n  - tenantName: amazon\n    thirdThingsId: 10.0.0.5:1883\n"
+        );
+
+        // Without includes the config is kept as is
+        let contents = "# comment\nspec: {}\n";
+        assert_eq!(preprocess_with(contents, &dir, &env).unwrap(), contents);
+
+        fs::write(dir.join("loop.yml"), "!include loop.yml\n").unwrap();
+        let e = preprocess_with("spec: !include loop.yml\n", &dir, &env).unwrap_err();
+        assert!(e.to_string().ends_with("includes itself"), "{}", e);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
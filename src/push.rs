@@ -0,0 +1,424 @@
+use std::fmt::Write as _;
+use std::io::{Error, Result};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::{task::JoinHandle, time};
+
+use crate::stressing_registry::{self, MetricKind, MetricRegistry};
+use crate::util::MyClient;
+
+const DEFAULT_PUSH_INTERVAL_SECS: &str = "10";
+
+const PUSH_JOB: &str = "mqtt_bench";
+
+#[derive(Debug, Clone)]
+pub struct PushOptions {
+    // base url of a Pushgateway compatible endpoint
+    pub gateway: Option<String>,
+    // url of a Prometheus remote-write endpoint
+    pub remote_write: Option<String>,
+    pub interval: Duration,
+}
+
+pub fn args() -> Vec<clap::Arg> {
+    vec![
+        clap::arg!(--"push-gateway" <URL>)
+            .help("Push the metrics to the Pushgateway, e.g. http://127.0.0.1:9091"),
+        clap::arg!(--"remote-write" <URL>)
+            .help("Push the metrics to the Prometheus remote-write url"),
+        clap::arg!(--"push-interval" <SECS>)
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value(DEFAULT_PUSH_INTERVAL_SECS)
+            .help("Seconds between two pushes of the metrics"),
+    ]
+}
+
+impl PushOptions {
+    pub fn from_matches(matches: &clap::ArgMatches) -> PushOptions {
+        PushOptions {
+            gateway: matches.get_one::<String>("push-gateway").cloned(),
+            remote_write: matches.get_one::<String>("remote-write").cloned(),
+            interval: Duration::from_secs(*matches.get_one::<u64>("push-interval").unwrap()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.gateway.is_some() || self.remote_write.is_some()
+    }
+}
+
+// Series is a single sample in the push payload
+#[derive(Debug, Clone)]
+struct Series {
+    name: String,
+    kind: &'static str,
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+// Pusher pushes the snapshots of the registries, one per spec, to the push
+// endpoints
+pub struct Pusher {
+    http_client: Arc<MyClient>,
+    registries: Vec<Arc<MetricRegistry>>,
+    options: PushOptions,
+    prefix: Option<String>,
+    instance: String,
+}
+
+impl Pusher {
+    pub fn new(
+        http_client: Arc<MyClient>,
+        registries: Vec<Arc<MetricRegistry>>,
+        options: PushOptions,
+        prefix: Option<String>,
+        instance: String,
+    ) -> Pusher {
+        Pusher {
+            http_client,
+            registries,
+            options,
+            prefix,
+            instance,
+        }
+    }
+
+    // push sends the current snapshot to all configured endpoints, failures
+    // are reported but don't stop the test.
+    pub async fn push(&self) {
+        let series = self.collect();
+        if let Some(gateway) = &self.options.gateway {
+            if let Err(e) = self.push_gateway(gateway, &series).await {
+                tracing::warn!(gateway = %gateway, error = %e, "push metrics failed");
+            }
+        }
+        if let Some(url) = &self.options.remote_write {
+            if let Err(e) = self.remote_write(url, &series).await {
+                tracing::warn!(url = %url, error = %e, "remote write metrics failed");
+            }
+        }
+    }
+
+    fn metric_name(&self, name: &str) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{}_{}", prefix, name),
+            None => name.to_string(),
+        }
+    }
+
+    fn collect(&self) -> Vec<Series> {
+        let mut series = vec![];
+        for registry in &self.registries {
+            series.extend(self.collect_registry(registry));
+        }
+        // The series of a metric are grouped under a single TYPE line
+        series.sort_by(|a, b| base_name(a).cmp(base_name(b)));
+        series
+    }
+
+    fn collect_registry(&self, registry: &MetricRegistry) -> Vec<Series> {
+        let labels = registry.labels();
+        let mut series: Vec<Series> = registry
+            .samples()
+            .into_iter()
+            .map(|sample| Series {
+                name: self.metric_name(&sample.name),
+                kind: match sample.kind {
+                    MetricKind::Counter => "counter",
+                    MetricKind::Gauge => "gauge",
+                },
+                labels: [labels.clone(), sample.labels].concat(),
+                value: sample.value,
+            })
+            .collect();
+
+        // The latency histograms are pushed as summaries
+        for summary in registry.summaries() {
+            let name = self.metric_name(summary.name);
+            let summary_labels = [labels.clone(), summary.labels].concat();
+            let latency = summary.latency;
+            for (quantile, value) in [
+                ("0.5", latency.p50),
+                ("0.9", latency.p90),
+                ("0.99", latency.p99),
+                ("1", latency.max),
+            ] {
+                let mut quantile_labels = summary_labels.clone();
+                quantile_labels.push((
+                    stressing_registry::QUANTILE.to_string(),
+                    quantile.to_string(),
+                ));
+                series.push(Series {
+                    name: name.clone(),
+                    kind: "summary",
+                    labels: quantile_labels,
+                    value: value / 1000.0,
+                });
+            }
+            series.push(Series {
+                name: name.clone() + "_sum",
+                kind: "summary",
+                labels: summary_labels.clone(),
+                value: latency.mean * latency.count as f64 / 1000.0,
+            });
+            series.push(Series {
+                name: name + "_count",
+                kind: "summary",
+                labels: summary_labels,
+                value: latency.count as f64,
+            });
+        }
+        series
+    }
+
+    async fn push_gateway(&self, gateway: &str, series: &[Series]) -> Result<()> {
+        let mut url = reqwest::Url::parse(gateway).map_err(Error::other)?;
+        url.path_segments_mut()
+            .map_err(|_| Error::other("invalid push gateway url"))?
+            .pop_if_empty()
+            .extend(["metrics", "job", PUSH_JOB, "instance", &self.instance]);
+
+        let response = self
+            .http_client
+            .client
+            .put(url)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(text_format(series))
+            .send()
+            .await
+            .map_err(Error::other)?;
+        check_status(response)
+    }
+
+    async fn remote_write(&self, url: &str, series: &[Series]) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&write_request(series, timestamp))
+            .map_err(Error::other)?;
+
+        let response = self
+            .http_client
+            .client
+            .post(url)
+            .header("Content-Encoding", "snappy")
+            .header("Content-Type", "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body)
+            .send()
+            .await
+            .map_err(Error::other)?;
+        check_status(response)
+    }
+}
+
+fn check_status(response: reqwest::Response) -> Result<()> {
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(Error::other(format!("status {}", response.status())))
+    }
+}
+
+// start spawns a task pushing the metrics every interval
+pub fn start(pusher: Arc<Pusher>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval(pusher.options.interval);
+        loop {
+            interval.tick().await;
+            pusher.push().await;
+        }
+    })
+}
+
+// base_name is the name of the TYPE line, summary series share the TYPE line
+// of their base name
+fn base_name(s: &Series) -> &str {
+    s.name
+        .strip_suffix("_sum")
+        .or_else(|| s.name.strip_suffix("_count"))
+        .filter(|_| s.kind == "summary")
+        .unwrap_or(&s.name)
+}
+
+// text_format renders the series in the Prometheus text exposition format
+fn text_format(series: &[Series]) -> String {
+    let mut out = String::new();
+    let mut last_type = "";
+    for s in series {
+        let base = base_name(s);
+        if last_type != base {
+            let _ = writeln!(out, "# TYPE {} {}", base, s.kind);
+            last_type = base;
+        }
+        let labels: Vec<String> = s
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+            .collect();
+        let _ = writeln!(out, "{}{{{}}} {}", s.name, labels.join(","), s.value);
+    }
+    out
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// write_request encodes the series as a remote-write WriteRequest protobuf:
+//
+//   WriteRequest { repeated TimeSeries timeseries = 1; }
+//   TimeSeries   { repeated Label labels = 1; repeated Sample samples = 2; }
+//   Label        { string name = 1; string value = 2; }
+//   Sample       { double value = 1; int64 timestamp = 2; }
+fn write_request(series: &[Series], timestamp: i64) -> Vec<u8> {
+    let mut request = Vec::new();
+    for s in series {
+        let mut labels = s.labels.clone();
+        labels.push(("__name__".to_string(), s.name.clone()));
+        labels.sort();
+
+        let mut time_series = Vec::new();
+        for (name, value) in labels {
+            let mut label = Vec::new();
+            put_bytes(&mut label, 1, name.as_bytes());
+            put_bytes(&mut label, 2, value.as_bytes());
+            put_bytes(&mut time_series, 1, &label);
+        }
+        let mut sample = Vec::new();
+        put_key(&mut sample, 1, 1);
+        sample.extend_from_slice(&s.value.to_le_bytes());
+        put_key(&mut sample, 2, 0);
+        put_varint(&mut sample, timestamp as u64);
+        put_bytes(&mut time_series, 2, &sample);
+
+        put_bytes(&mut request, 1, &time_series);
+    }
+    request
+}
+
+fn put_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    put_varint(buf, field << 3 | wire_type);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_key(buf, field, 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::{put_varint, PushOptions, Pusher};
+    use crate::stressing_registry::MetricRegistry;
+    use crate::util::MyClient;
+
+    fn pusher(gateway: Option<String>, remote_write: Option<String>) -> Pusher {
+        let reg = MetricRegistry::new("task-demo".to_string())
+            .with_labels(vec![("host".to_string(), "bench-0".to_string())]);
+        reg.start_task();
+        reg.sent_packets_inc();
+        reg.ack_latency_observe(Duration::from_millis(5));
+        let options = PushOptions {
+            gateway,
+            remote_write,
+            interval: Duration::from_secs(10),
+        };
+        Pusher::new(
+            Arc::new(MyClient::new()),
+            vec![Arc::new(reg)],
+            options,
+            Some("mqtt_bench".to_string()),
+            "bench-0".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, 300);
+        assert_eq!(buf, vec![0xac, 0x02]);
+    }
+
+    #[tokio::test]
+    async fn test_push_gateway() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path("/metrics/job/mqtt_bench/instance/bench-0"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let pusher = pusher(Some(server.uri()), None);
+        let series = pusher.collect();
+        pusher.push_gateway(&server.uri(), &series).await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let body = String::from_utf8(requests[0].body.clone()).unwrap();
+        println!("{}", body);
+        assert!(body.contains("# TYPE mqtt_bench_sent_packets counter"));
+        assert!(body.contains(r#"mqtt_bench_sent_packets{host="bench-0",task_name="task-demo"} 1"#));
+        assert!(body.contains(r#"mqtt_bench_task_status{host="bench-0",task_name="task-demo"} 1"#));
+        assert!(body.contains("# TYPE mqtt_bench_ack_latency_seconds summary"));
+        assert!(body.contains("mqtt_bench_ack_latency_seconds_count"));
+    }
+
+    #[tokio::test]
+    async fn test_remote_write() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/write"))
+            .and(header("Content-Encoding", "snappy"))
+            .and(header("Content-Type", "application/x-protobuf"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let url = format!("{}/api/v1/write", server.uri());
+        let pusher = pusher(None, Some(url.clone()));
+        let series = pusher.collect();
+        pusher.remote_write(&url, &series).await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let body = snap::raw::Decoder::new()
+            .decompress_vec(&requests[0].body)
+            .unwrap();
+        let needle = b"mqtt_bench_sent_packets";
+        assert!(body.windows(needle.len()).any(|w| w == needle));
+    }
+
+    #[tokio::test]
+    async fn test_push_failure_is_reported() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        let pusher = pusher(Some(server.uri()), None);
+        let series = pusher.collect();
+        assert!(pusher.push_gateway(&server.uri(), &series).await.is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::fmt::Write as _;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::config::{Config, GroupVersionKind, Spec};
+use crate::overrides;
+use crate::stressing::{get_payload, get_topic};
+use crate::util::TokenRequest;
+use crate::validate;
+
+// Device is what a task would use for its device, nothing is connected
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    pub index: usize,
+    pub client_id: String,
+    pub topic: String,
+    // dynamic token request, None without a dynamic token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_request: Option<TokenRequest>,
+    // size of the decoded payload, None if the payload can't be decoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub fn devices(config: &Config) -> impl Iterator<Item = Device> + '_ {
+    (0..config.things_info.len()).map(|idx| {
+        let things = match config.things_info.device(idx) {
+            Ok(things) => things,
+            Err(e) => {
+                return Device {
+                    index: idx,
+                    client_id: String::new(),
+                    topic: String::new(),
+                    token_request: None,
+                    payload_size: None,
+                    error: Some(e.to_string()),
+                }
+            }
+        };
+        let client_id = config.get_client_id(&things);
+        let topic = get_topic(config, &things, &client_id);
+        let token_request = if config.dynamic_token.url.is_empty() {
+            None
+        } else {
+            Some(config.token_request(&things))
+        };
+        let payload = get_payload(config, &things);
+        Device {
+            index: idx,
+            client_id,
+            topic,
+            token_request,
+            payload_size: payload.as_ref().ok().map(|payload| payload.len()),
+            error: payload.err().map(|e| e.to_string()),
+        }
+    })
+}
+
+fn format_text(device: &Device) -> String {
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "[{}] client id: {}, topic: {}",
+        device.index, device.client_id, device.topic
+    );
+    match (&device.payload_size, &device.error) {
+        (Some(size), _) => {
+            let _ = write!(out, ", payload: {} bytes", size);
+        }
+        (None, Some(e)) => {
+            let _ = write!(out, ", payload: {}", e);
+        }
+        (None, None) => {}
+    }
+    if let Some(request) = &device.token_request {
+        let _ = write!(out, "\n    token request: {}", request.method);
+        if !request.query.is_empty() {
+            let query: Vec<String> = request
+                .query
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            let _ = write!(out, " ?{}", query.join("&"));
+        }
+        if let Some(body) = &request.body {
+            let _ = write!(out, " {}", body.trim());
+        }
+        if !request.headers.is_empty() {
+            let headers: Vec<String> = request
+                .headers
+                .iter()
+                .map(|(name, value)| format!("{}: {}", name, value))
+                .collect();
+            let _ = write!(out, "\n    token headers: {}", headers.join(", "));
+        }
+    }
+    out
+}
+
+pub fn command() -> clap::Command {
+    clap::Command::new("render")
+        .about("Print the client id, topic, token request and payload size of every device without connecting")
+        .arg(
+            clap::arg!(--"file" <PATH>)
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .short(Some('f'))
+                .required(true)
+                .help("Config file for stress test"),
+        )
+        .arg(
+            clap::arg!(--"max-connections" <NUM>)
+                .value_parser(clap::value_parser!(usize))
+                .short(Some('c'))
+                .help("Render the first NUM devices only"),
+        )
+        .arg(
+            clap::arg!(--"format" <FORMAT>)
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .help("Format of the output, json writes a JSON object per device"),
+        )
+        .args(overrides::args())
+}
+
+// execute prints the rendered devices, the exit code is non-zero when the
+// config can't be parsed or a payload can't be decoded
+pub fn execute(matches: &clap::ArgMatches) -> i32 {
+    let path = matches.get_one::<std::path::PathBuf>("file").unwrap();
+    let config = match validate::read(path, &overrides::from_matches(matches)) {
+        Ok(config) => config,
+        Err(code) => return code,
+    };
+    let mut plan = match config.parse() {
+        Ok((_, plan)) => plan,
+        Err(issue) => {
+            eprintln!("{}: {}", path.display(), issue);
+            return 1;
+        }
+    };
+    let issues = validate::load(&mut plan, path.parent().unwrap_or(Path::new(".")));
+    for issue in &issues {
+        eprintln!("{}: {}", path.display(), issue);
+    }
+    if !issues.is_empty() {
+        return 1;
+    }
+    let max = *matches
+        .get_one::<usize>("max-connections")
+        .unwrap_or(&usize::MAX);
+    let json = matches.get_one::<String>("format").unwrap() == "json";
+
+    let mut failed = false;
+    for stressing in &plan.specs {
+        let name = stressing.meta().name;
+        let Spec::Publish(config) = &stressing.spec else {
+            eprintln!("{}: kind {} has no device", name, stressing.kind());
+            failed = true;
+            continue;
+        };
+        if !json && plan.specs.len() > 1 {
+            println!("# {}", name);
+        }
+        for device in devices(config).take(max) {
+            failed |= device.error.is_some();
+            if json {
+                println!("{}", serde_json::to_string(&device).unwrap_or_default());
+            } else {
+                println!("{}", format_text(&device));
+            }
+        }
+    }
+    i32::from(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{devices, format_text};
+    use crate::config::{spec_from_str, Spec};
+
+    const YAML: &str = r#"group: github.com/zhao-kun/mqtt-bench
+version: v1.0.1
+kind: publish
+metaData:
+  name: task-demo
+spec:
+  dynamicToken:
+    url: http://localhost:8080/v1/
+    payload: '{"username": "${tenantName}", "password": "${password}"}'
+    tokenExtractor: ".data.token"
+  topicTemplate: /prefix/${tenantName}/${infoModelName}/${thirdThingsId}
+  isPayloadBase64: false
+  thingsPayloads:
+   "google": "hello world"
+  thingsInfo:
+  - tenantName: "google"
+    infoModelName: "demo_v1"
+    thirdThingsId: thirdThingsID
+    password: "things_password"
+  - tenantName: "amazon"
+    infoModelName: "demo_v2"
+    thirdThingsId: device_2
+    password: "things_password"
+"#;
+
+    #[test]
+    fn test_render_devices() {
+        let config = match spec_from_str(YAML).unwrap().spec {
+            Spec::Publish(config) => config,
+            _ => panic!("should be publish spec"),
+        };
+        let devices: Vec<_> = devices(&config).collect();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(
+            format_text(&devices[0]),
+            "[0] client id: demo_v1:thirdThingsID, topic: /prefix/google/demo_v1/thirdThingsID, payload: 11 bytes\n    token request: POST {\"username\": \"google\", \"password\": \"things_password\"}\n    token headers: Content-Type: application/json"
+        );
+        assert_eq!(devices[1].payload_size, None);
+        assert_eq!(
+            devices[1].error.as_deref(),
+            Some("invalid config: things device_2 of tenant amazon hasn't payload")
+        );
+    }
+}
@@ -0,0 +1,363 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::{fs, io::Result, path::Path};
+
+use crate::config::{Threshold, ThresholdMetric, ThresholdOp};
+use crate::stressing_registry::{BreakdownSnapshot, LatencySummary, Snapshot};
+
+// Report summarizes a run, it is built from the registry snapshot taken
+// after all tasks were finished.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub task_name: String,
+    // labels of the exported metrics, including the metaData labels
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub elapsed_secs: f64,
+    pub connections: ConnectionReport,
+    pub publish: PublishReport,
+    pub ack_latency: LatencySummary,
+    // durations of the connection setup phases, by the phase name
+    #[serde(default)]
+    pub phases: BTreeMap<String, LatencySummary>,
+    pub errors: ErrorReport,
+    // per broker, tenant and info model counters
+    #[serde(default)]
+    pub breakdowns: Vec<BreakdownSnapshot>,
+    // reports of the specs of a plan, this report combines them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub specs: Vec<Report>,
+    // effective config of the spec with the defaults marked, see explain
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub config: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionReport {
+    pub planned: usize,
+    pub accepted: usize,
+    pub finished: usize,
+    pub failed: usize,
+    // percentage of the planned tasks whose connection was accepted
+    pub success_rate: f64,
+    // received CONNACKs by the return code
+    #[serde(default)]
+    pub connack_codes: BTreeMap<String, usize>,
+    // closed established connections by the reason category
+    #[serde(default)]
+    pub disconnects: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublishReport {
+    pub sent: usize,
+    pub acked: usize,
+    pub target: usize,
+    // acknowledged publishes per second
+    pub throughput: f64,
+    // percentage of the acknowledged publishes against the target
+    pub achieved_rate: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorReport {
+    pub failed_tasks: usize,
+    pub invalid_pubacks: usize,
+    pub timeout_pubacks: usize,
+    // failed tasks by the reason category, e.g. dns, connack, eof
+    #[serde(default)]
+    pub reasons: BTreeMap<String, usize>,
+}
+
+impl Report {
+    pub fn from_snapshot(snapshot: &Snapshot) -> Report {
+        let elapsed_secs = snapshot.elapsed.as_secs_f64();
+        Report {
+            task_name: snapshot.task_name.clone(),
+            labels: snapshot.labels.iter().cloned().collect(),
+            elapsed_secs,
+            connections: ConnectionReport {
+                planned: snapshot.planned_tasks,
+                accepted: snapshot.accepted_connections,
+                finished: snapshot.finished_tasks,
+                failed: snapshot.failed_tasks(),
+                success_rate: percentage(snapshot.accepted_connections, snapshot.planned_tasks),
+                connack_codes: snapshot.connack_codes.clone(),
+                disconnects: snapshot.disconnects.clone(),
+            },
+            publish: PublishReport {
+                sent: snapshot.sent_packets,
+                acked: snapshot.publish_packets,
+                target: snapshot.target_publishes,
+                throughput: if elapsed_secs > 0.0 {
+                    snapshot.publish_packets as f64 / elapsed_secs
+                } else {
+                    0.0
+                },
+                achieved_rate: percentage(snapshot.publish_packets, snapshot.target_publishes),
+            },
+            ack_latency: snapshot.ack_latency.clone(),
+            phases: snapshot.phases.clone(),
+            errors: ErrorReport {
+                failed_tasks: snapshot.failed_tasks(),
+                invalid_pubacks: snapshot.invalid_pubacks,
+                timeout_pubacks: snapshot.timeout_pubacks,
+                reasons: snapshot.task_failures.clone(),
+            },
+            breakdowns: snapshot.breakdowns.clone(),
+            specs: vec![],
+            config: String::new(),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Report> {
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn write_file(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn format_labels(&self) -> String {
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        labels.join(", ")
+    }
+
+    // metric returns the value of the metric, None for a latency when nothing
+    // was acknowledged, the percentiles of an empty histogram are 0
+    pub fn metric(&self, metric: ThresholdMetric) -> Option<f64> {
+        let latency = |value: f64| (self.ack_latency.count > 0).then_some(value);
+        match metric {
+            ThresholdMetric::AckLatencyP50 => latency(self.ack_latency.p50),
+            ThresholdMetric::AckLatencyP90 => latency(self.ack_latency.p90),
+            ThresholdMetric::AckLatencyP99 => latency(self.ack_latency.p99),
+            ThresholdMetric::AckLatencyMax => latency(self.ack_latency.max),
+            ThresholdMetric::ConnectSuccessRate => Some(self.connections.success_rate),
+            ThresholdMetric::AchievedRate => Some(self.publish.achieved_rate),
+            ThresholdMetric::FailedTasks => Some(self.errors.failed_tasks as f64),
+            ThresholdMetric::InvalidPubacks => Some(self.errors.invalid_pubacks as f64),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "task {} finished in {:.1}s",
+            self.task_name, self.elapsed_secs
+        )?;
+        if !self.labels.is_empty() {
+            writeln!(f, "labels: {}", self.format_labels())?;
+        }
+        writeln!(
+            f,
+            "connections: planned {}, accepted {}, finished {}, failed {}, success rate {:.2}%",
+            self.connections.planned,
+            self.connections.accepted,
+            self.connections.finished,
+            self.connections.failed,
+            self.connections.success_rate
+        )?;
+        writeln!(
+            f,
+            "publish: sent {}, acked {}, target {}, throughput {:.2}/s, achieved rate {:.2}%",
+            self.publish.sent,
+            self.publish.acked,
+            self.publish.target,
+            self.publish.throughput,
+            self.publish.achieved_rate
+        )?;
+        writeln!(
+            f,
+            "ack latency: p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms, max {:.2}ms",
+            self.ack_latency.p50, self.ack_latency.p90, self.ack_latency.p99, self.ack_latency.max
+        )?;
+        for (phase, latency) in self.phases.iter() {
+            writeln!(
+                f,
+                "{} phase: p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms, max {:.2}ms",
+                phase, latency.p50, latency.p90, latency.p99, latency.max
+            )?;
+        }
+        writeln!(
+            f,
+            "errors: failed tasks {}, invalid pubacks {}, not ready {}",
+            self.errors.failed_tasks, self.errors.invalid_pubacks, self.errors.timeout_pubacks
+        )?;
+        for (name, counts) in [
+            ("connack codes", &self.connections.connack_codes),
+            ("disconnects", &self.connections.disconnects),
+            ("failure reasons", &self.errors.reasons),
+        ] {
+            if !counts.is_empty() {
+                writeln!(f, "{}: {}", name, format_counts(counts))?;
+            }
+        }
+        for b in self.breakdowns.iter() {
+            writeln!(
+                f,
+                "{} {}: connects {}, failures {}, publishes {}, acks {}, ack latency p99 {:.2}ms",
+                b.dimension,
+                b.key,
+                b.connects,
+                b.connect_failures,
+                b.publishes,
+                b.acks,
+                b.ack_latency.p99
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// format_counts formats the counts as `key count, key count`
+pub fn format_counts(counts: &BTreeMap<String, usize>) -> String {
+    let counts: Vec<String> = counts
+        .iter()
+        .map(|(key, count)| format!("{} {}", key, count))
+        .collect();
+    counts.join(", ")
+}
+
+fn percentage(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    part as f64 * 100.0 / total as f64
+}
+
+#[derive(Debug, Clone)]
+pub struct Verdict {
+    pub threshold: Threshold,
+    // None if the metric has no value, the threshold fails
+    pub actual: Option<f64>,
+    pub passed: bool,
+}
+
+pub fn evaluate(report: &Report, thresholds: &[Threshold]) -> Vec<Verdict> {
+    thresholds
+        .iter()
+        .map(|threshold| {
+            let actual = report.metric(threshold.metric);
+            let passed = actual.is_some_and(|actual| match threshold.op {
+                ThresholdOp::Lt => actual < threshold.value,
+                ThresholdOp::Le => actual <= threshold.value,
+                ThresholdOp::Gt => actual > threshold.value,
+                ThresholdOp::Ge => actual >= threshold.value,
+            });
+            Verdict {
+                threshold: threshold.clone(),
+                actual,
+                passed,
+            }
+        })
+        .collect()
+}
+
+pub fn verdict_table(verdicts: &[Verdict]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{:<20} {:<14} {:>12}  result",
+        "metric", "threshold", "actual"
+    );
+    for verdict in verdicts {
+        let threshold = format!("{} {}", verdict.threshold.op, verdict.threshold.value);
+        let actual = match verdict.actual {
+            Some(actual) => format!("{:.2}", actual),
+            None => "n/a".to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "{:<20} {:<14} {:>12}  {}",
+            verdict.threshold.metric.to_string(),
+            threshold,
+            actual,
+            if verdict.passed { "PASS" } else { "FAIL" }
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{evaluate, verdict_table, Report};
+    use crate::config::{Threshold, ThresholdMetric, ThresholdOp};
+    use crate::stressing_registry::MetricRegistry;
+
+    #[test]
+    fn test_evaluate_thresholds() {
+        let reg = MetricRegistry::new("task-demo".to_string())
+            .with_labels(vec![("env".to_string(), "staging".to_string())]);
+        reg.start_task();
+        reg.planned_tasks_add(4);
+        reg.target_publishes_add(10);
+        for _ in 0..3 {
+            reg.accepted_connections_inc();
+        }
+        for i in 0..9 {
+            reg.publish_packets_inc();
+            reg.ack_latency_observe(Duration::from_millis(10 + i));
+        }
+        reg.task_stopped();
+
+        let report = Report::from_snapshot(&reg.snapshot());
+        assert_eq!(report.connections.success_rate, 75.0);
+        assert_eq!(report.publish.achieved_rate, 90.0);
+
+        let thresholds = vec![
+            Threshold {
+                metric: ThresholdMetric::AckLatencyP99,
+                op: ThresholdOp::Lt,
+                value: 50.0,
+            },
+            Threshold {
+                metric: ThresholdMetric::ConnectSuccessRate,
+                op: ThresholdOp::Gt,
+                value: 99.9,
+            },
+            Threshold {
+                metric: ThresholdMetric::AchievedRate,
+                op: ThresholdOp::Ge,
+                value: 90.0,
+            },
+        ];
+        let verdicts = evaluate(&report, &thresholds);
+        assert!(verdicts[0].passed);
+        assert!(!verdicts[1].passed);
+        assert!(verdicts[2].passed);
+
+        let json = serde_json::to_string(&report).unwrap();
+        let parsed: Report = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.publish.acked, 9);
+        assert_eq!(parsed.labels.get("env").unwrap(), "staging");
+        assert!(report.to_string().contains("labels: env=staging"));
+
+        let table = verdict_table(&verdicts);
+        assert!(table.contains("connectSuccessRate   > 99.9"));
+        assert!(table.contains("FAIL"));
+
+        // A latency of a run without acknowledged publishes fails
+        let reg = MetricRegistry::new("task-demo".to_string());
+        reg.start_task();
+        reg.task_stopped();
+        let report = Report::from_snapshot(&reg.snapshot());
+        let verdicts = evaluate(&report, &thresholds[..1]);
+        assert_eq!(verdicts[0].actual, None);
+        assert!(!verdicts[0].passed);
+        assert!(verdict_table(&verdicts).contains("n/a  FAIL"));
+    }
+}
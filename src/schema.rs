@@ -0,0 +1,50 @@
+use crate::config::Stressing;
+
+// schema returns the JSON Schema of a config document, the descriptions are
+// the doc comments of the config types
+pub fn schema() -> String {
+    let schema = schemars::schema_for!(Stressing);
+    serde_json::to_string_pretty(&schema).unwrap() + "\n"
+}
+
+pub fn command() -> clap::Command {
+    clap::Command::new("schema")
+        .about("Print the JSON Schema of the config file, e.g. for the YAML language server")
+        .arg(
+            clap::arg!(--"output" <PATH>)
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .short(Some('o'))
+                .help("Write the schema to the file instead of stdout"),
+        )
+}
+
+pub fn execute(matches: &clap::ArgMatches) -> i32 {
+    let schema = schema();
+    match matches.get_one::<std::path::PathBuf>("output") {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, schema) {
+                eprintln!("write schema {} failed: {}", path.display(), e);
+                return 2;
+            }
+            0
+        }
+        None => {
+            print!("{}", schema);
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::schema;
+
+    // The published schema is generated by `mqtt-bench schema -o schema/config.schema.json`
+    #[test]
+    fn test_published_schema() {
+        assert!(
+            schema() == include_str!("../schema/config.schema.json"),
+            "schema/config.schema.json is out of date, regenerate it by the schema subcommand"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: &str = "10";
+const DEFAULT_LINGER_SECS: &str = "30";
+
+// Exit code of an interrupted run, like a shell reports a SIGINT
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+pub fn args() -> Vec<clap::Arg> {
+    vec![
+        clap::arg!(--"shutdown-timeout" <SECS>)
+            .value_parser(clap::value_parser!(u64))
+            .default_value(DEFAULT_SHUTDOWN_TIMEOUT_SECS)
+            .help("Seconds to wait for the in-flight acks after SIGINT or SIGTERM"),
+        clap::arg!(--"linger" <SECS>)
+            .value_parser(clap::value_parser!(u64))
+            .default_value(DEFAULT_LINGER_SECS)
+            .help(
+                "Seconds to keep the metrics endpoint up after the run, skipped when interrupted",
+            ),
+    ]
+}
+
+#[derive(Debug, Clone)]
+pub struct ShutdownOptions {
+    pub timeout: Duration,
+    pub linger: Duration,
+}
+
+impl ShutdownOptions {
+    pub fn from_matches(matches: &clap::ArgMatches) -> ShutdownOptions {
+        ShutdownOptions {
+            timeout: Duration::from_secs(*matches.get_one::<u64>("shutdown-timeout").unwrap()),
+            linger: Duration::from_secs(*matches.get_one::<u64>("linger").unwrap()),
+        }
+    }
+}
+
+// Shutdown tells the tasks the run was interrupted, every task holds a clone
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+    // how long a task waits for its in-flight ack once interrupted
+    pub timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn is_stopped(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    // stopped resolves once the run is interrupted
+    pub async fn stopped(&mut self) {
+        while !*self.rx.borrow_and_update() {
+            if self.rx.changed().await.is_err() {
+                // The sender is gone, the run can't be interrupted anymore
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+// listen interrupts the run on the first SIGINT or SIGTERM and exits the
+// process on the second one.
+pub fn listen(options: &ShutdownOptions) -> Shutdown {
+    let (tx, rx) = watch::channel(false);
+    let timeout = options.timeout;
+    tokio::spawn(async move {
+        signal().await;
+        tracing::warn!(
+            timeout_secs = timeout.as_secs(),
+            "interrupted, waiting for the in-flight acks, interrupt again to exit immediately"
+        );
+        let _ = tx.send(true);
+        signal().await;
+        tracing::warn!("interrupted again, exiting");
+        crate::events::flush().await;
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    });
+    Shutdown { rx, timeout }
+}
+
+#[cfg(unix)]
+async fn signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = terminate.recv() => {},
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::watch;
+
+    use super::Shutdown;
+
+    #[tokio::test]
+    async fn test_stopped() {
+        let (tx, rx) = watch::channel(false);
+        let mut shutdown = Shutdown {
+            rx,
+            timeout: Duration::from_secs(1),
+        };
+        assert!(!shutdown.is_stopped());
+        let waiting = tokio::time::timeout(Duration::from_millis(20), shutdown.stopped()).await;
+        assert!(waiting.is_err());
+
+        tx.send(true).unwrap();
+        shutdown.stopped().await;
+        assert!(shutdown.is_stopped());
+    }
+}
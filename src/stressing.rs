@@ -1,18 +1,40 @@
 use base64::{engine::general_purpose, Engine as _};
-use mqtt::{packet::*, Encodable};
+use mqtt::{packet::*, Encodable, TopicName};
 use rand::{self, Rng};
-use std::{
-    io::{Error, ErrorKind, Result},
-    panic,
-    sync::Arc,
-    time::Duration,
+use std::{borrow::Cow, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{lookup_host, TcpStream},
+    select,
+    sync::broadcast,
+    time,
+    time::Instant,
 };
-use tokio::{io::AsyncWriteExt, net::TcpStream, select, sync::broadcast, time, time::Instant};
+use tokio_native_tls::{native_tls, TlsConnector};
+use tracing::{debug, error, warn};
 
 use crate::config::{self, get_things_password};
-use crate::stressing_registry;
+use crate::error::{connack_code, BenchError, BenchResult};
+use crate::events::{millis, ConnectionEvents, Event};
+use crate::logging;
+use crate::otlp::ConnectionTrace;
+use crate::packet_log;
+use crate::shutdown::Shutdown;
+use crate::stressing_registry::{self, Breakdowns, Phase};
 use crate::util::{render_template, MyClient};
 
+// Max random delay before a task starts connecting, it spreads the
+// connections of all tasks over the ramp-up window.
+pub const RAMP_UP_MILLS: u64 = 120000;
+
+// Max random delay between getting the password and connecting the broker
+pub const CONNECT_JITTER_MILLS: u64 = 30000;
+
+// BrokerStream is the connection to the broker, plain TCP or TLS
+trait BrokerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> BrokerStream for T {}
+
 #[derive(PartialEq, Debug)]
 enum StressState {
     Connecting,
@@ -25,20 +47,99 @@ pub async fn run(
     registry: Arc<stressing_registry::MetricRegistry>,
     cfg: Arc<config::Config>,
     things_idx: usize,
+    tls: Option<TlsConnector>,
+    mut shutdown: Shutdown,
 ) {
-    // Send ConnectPacket to the broker
-    let mut state = StressState::Connecting;
-    let mut stream;
-    let client_id = cfg.get_client_id(things_idx);
-    shuffle_sleep(120000).await;
-    if let Ok(str) = connect_broker(&cfg, things_idx, &client_id, http_client).await {
-        stream = str;
-    } else {
-        registry.exited_tasks_inc();
-        return;
-    }
+    let things = match read_device(&cfg, things_idx).await {
+        Ok(things) => things,
+        Err(e) => {
+            error!(things_idx, error = %e, "task exited");
+            registry.exited_tasks_inc();
+            registry.task_failure_inc(e.reason());
+            // Without the device there is no client id, the task is named
+            // after the index of its device
+            let task_id = format!("device-{}", things_idx);
+            ConnectionEvents::new(&task_id).emit(Event::TaskExit {
+                reason: e.reason().to_string(),
+                sent: 0,
+                acked: 0,
+            });
+            ConnectionTrace::new(&task_id, &config::ThingsInfo::default())
+                .finish(Some(e.to_string()));
+            return;
+        }
+    };
+    let client_id = cfg.get_client_id(&things);
+    let traced = logging::is_traced(&client_id);
+    let mut trace = ConnectionTrace::new(&client_id, &things);
+    let events = ConnectionEvents::new(&client_id);
+    let broker_addr = pick_broker(&cfg);
+    trace.set_broker(&broker_addr);
+    let breakdowns = new_breakdowns(&registry, &things, &broker_addr);
+
+    // Calculating the payload and the topic
+    let prepared = get_payload(&cfg, &things).and_then(|payload| {
+        let topic = get_topic(&cfg, &things, &client_id);
+        let topic_name = TopicName::new(topic.clone())
+            .map_err(|e| BenchError::Config(format!("invalid topic {}: {}", topic, e)))?;
+        Ok((payload, topic_name))
+    });
+    let (payload, topic) = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            error!(client_id = %client_id, error = %e, "task exited");
+            registry.exited_tasks_inc();
+            registry.task_failure_inc(e.reason());
+            events.emit(Event::TaskExit {
+                reason: e.reason().to_string(),
+                sent: 0,
+                acked: 0,
+            });
+            trace.finish(Some(e.to_string()));
+            return;
+        }
+    };
 
-    let (mut rx, mut tx) = stream.split();
+    // Send ConnectPacket to the broker, an interrupted run stops connecting
+    let connecting = async {
+        shuffle_sleep(RAMP_UP_MILLS).await;
+        connect_broker(
+            &cfg,
+            &things,
+            &client_id,
+            &broker_addr,
+            http_client,
+            tls.as_ref(),
+            &registry,
+            &mut trace,
+            &events,
+        )
+        .await
+    };
+    let result = select! {
+        result = connecting => result,
+        _ = shutdown.stopped() => Err(BenchError::Interrupted),
+    };
+    let stream = match result {
+        Ok(stream) => stream,
+        Err(e) => {
+            registry.exited_tasks_inc();
+            registry.task_failure_inc(e.reason());
+            if e.is_connect_failure() {
+                breakdowns.connect_failures_inc();
+            }
+            events.emit(Event::TaskExit {
+                reason: e.reason().to_string(),
+                sent: 0,
+                acked: 0,
+            });
+            trace.finish(Some(e.to_string()));
+            return;
+        }
+    };
+    let connect_sent = Instant::now();
+
+    let (mut rx, mut tx) = io::split(stream);
 
     // Increases running task counter
     registry.running_tasks_inc();
@@ -52,72 +153,127 @@ pub async fn run(
     let (tx_ch, _rx) = broadcast::channel(10);
     let mut rx_ch = tx_ch.subscribe();
 
-    // Calculating the payload
-    let payload = get_payload(&cfg, things_idx);
-
-    let loops = cfg.duration * 1000 / cfg.think_time;
+    let loops = publish_loops(&cfg);
+    let mut state = StressState::Connecting;
     let mut sent = 0;
     let mut sending = 0;
     let mut sendack = 0;
-    let topic = get_topic(&cfg, things_idx, &client_id);
+    let mut published_at = Instant::now();
+    let mut exit_error: Option<BenchError> = None;
+    // Set once the run is interrupted, the in-flight PUBLISH is waited until it
+    let mut deadline: Option<Instant> = None;
+    let shutdown_timeout = shutdown.timeout;
 
     // Main loop
     loop {
         if sendack >= loops {
-            println!("client_id {} normaly finished,", client_id);
+            debug!(client_id = %client_id, "all publishes were acknowledged");
+            break;
+        }
+        if deadline.is_some() && state != StressState::Publishing {
+            exit_error = Some(BenchError::Interrupted);
             break;
         }
         select! {
+            _ = shutdown.stopped(), if deadline.is_none() => {
+                deadline = Some(Instant::now() + shutdown_timeout);
+            },
+            _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                warn!(client_id = %client_id, "the in-flight PUBLISH wasn't acknowledged before the shutdown deadline");
+                exit_error = Some(BenchError::Interrupted);
+                break;
+            },
             _ = heartbeat.tick() => {
-                if let Ok(packet) = new_publish_packet(&client_id, &state, &topic,  &payload){
-                    tx_ch.send(packet).unwrap();
+                // No more publishes once the run is interrupted
+                if deadline.is_some() {
+                    continue;
+                }
+                if let Some(packet) = new_publish_packet(&state, &topic, &payload) {
+                    // Sending fails only without receivers, rx_ch lives as long as the task
+                    let _ = tx_ch.send(packet);
                     sending += 1;
-                }else {
+                } else {
+                    packet_log!(traced, client_id = %client_id, state = ?state, "skip PUBLISH, the previous one isn't acknowledged");
                     registry.timeout_pubacks_inc();
                 }
             },
             result = rx_ch.recv() => {
-                let packet = result.unwrap();
+                let Ok(packet) = result else {
+                    continue;
+                };
                 let mut buf = Vec::new();
-                packet.encode(&mut buf).unwrap();
-                tx.write_all(&buf[..]).await.unwrap();
+                if let Err(e) = packet.encode(&mut buf) {
+                    exit_error = Some(BenchError::Protocol(e.to_string()));
+                    break;
+                }
+                if let Err(e) = tx.write_all(&buf[..]).await {
+                    exit_error = Some(e.into());
+                    break;
+                }
                 state = StressState::Publishing;
-                sent = sent + 1;
+                published_at = Instant::now();
+                sent += 1;
+                if sent == 1 {
+                    events.emit(Event::FirstPublish);
+                }
+                packet_log!(traced, client_id = %client_id, topic = &**topic, "sent PUBLISH");
+                registry.sent_packets_inc();
+                breakdowns.publishes_inc();
             },
             result = VariablePacket::parse(&mut rx) => {
                 let packet = match result {
                     Ok(packet) => packet,
                     Err(e) => {
-                        println!("parse packet error:{}", e);
+                        exit_error = Some(e.into());
                         break;
                     }
                 };
+                packet_log!(traced, client_id = %client_id, packet = ?packet, state = ?state, "received packet");
 
                 match packet {
-                    VariablePacket::PingrespPacket(..) => {
-                        println!("Receiving PINGRESP from broker ..");
-                    }
+                    VariablePacket::PingrespPacket(..) => {}
                     VariablePacket::ConnackPacket(_ack) => {
-                        if state == StressState::Connecting && _ack.connect_return_code() == mqtt::control::ConnectReturnCode::ConnectionAccepted{
-                            state = StressState::Published;
-                            println!("client_id: {} connection was established", client_id);
-                            registry.established_connection_inc();
-                            registry.ongoing_connection_decr();
-                        } else {
-                            println!("client_ID: {} failed to authorize, early exited, recv invalid connack {:?} under the state {:?}, task ended!",client_id, _ack, state);
-                            stream.shutdown().await.unwrap();
-                            registry.ongoing_connection_decr();
-                            registry.exited_tasks_inc();
-                            return;
+                        let code = connack_code(_ack.connect_return_code());
+                        registry.connack_code_inc(code);
+                        registry.phase_observe(Phase::Connack, connect_sent.elapsed());
+                        events.emit(Event::Connack {
+                            code: code.to_string(),
+                            elapsed_ms: millis(connect_sent.elapsed()),
+                        });
+                        if state != StressState::Connecting {
+                            exit_error = Some(BenchError::Protocol(format!("received CONNACK under the state {:?}", state)));
+                            break;
+                        }
+                        if _ack.connect_return_code() != mqtt::control::ConnectReturnCode::ConnectionAccepted {
+                            let e = BenchError::Connack(code);
+                            trace.span("connack", connect_sent.elapsed(), Some(e.to_string()));
+                            breakdowns.connect_failures_inc();
+                            exit_error = Some(e);
+                            break;
                         }
+                        state = StressState::Published;
+                        debug!(client_id = %client_id, "connection was established");
+                        registry.accepted_connections_inc();
+                        breakdowns.connects_inc();
+                        trace.span("connack", connect_sent.elapsed(), None);
+                        registry.established_connection_inc();
+                        registry.ongoing_connection_decr();
                     }
                     VariablePacket::PubackPacket(_ack) => {
                         if state == StressState::Publishing {
                             state = StressState::Published;
                             sendack +=1;
                             registry.publish_packets_inc();
+                            let latency = published_at.elapsed();
+                            registry.ack_latency_observe(latency);
+                            if sendack == 1 {
+                                registry.phase_observe(Phase::FirstPuback, latency);
+                            }
+                            breakdowns.acks_inc();
+                            breakdowns.ack_latency_observe(latency);
+                            trace.span("publish", latency, None);
                         } else {
-                            println!("client_id: {} recv invalid Puback, puback should be return when state is publishing", client_id);
+                            warn!(client_id = %client_id, state = ?state, "received PUBACK while no PUBLISH is in flight");
                             registry.invalid_pubacks_inc();
                         }
                     }
@@ -128,150 +284,335 @@ pub async fn run(
         }
     }
 
-    println!(
-        "client_id: {} task finished, total sent {}, sending {}, sendack {}",
-        client_id, sent, sending, sendack
-    );
+    let reason = exit_error.as_ref().map_or("finished", |e| e.reason());
+    match &exit_error {
+        Some(BenchError::Interrupted) => {
+            debug!(client_id = %client_id, sent, acked = sendack, "task interrupted")
+        }
+        Some(e) => {
+            warn!(client_id = %client_id, reason, error = %e, sent, acked = sendack, "task exited")
+        }
+        None => debug!(client_id = %client_id, sent, sending, acked = sendack, "task finished"),
+    }
+    events.emit(Event::Disconnect {
+        reason: reason.to_string(),
+    });
+    events.emit(Event::TaskExit {
+        reason: reason.to_string(),
+        sent,
+        acked: sendack as usize,
+    });
+    // Say goodbye to the broker unless the connection is already broken
+    let healthy = matches!(exit_error, None | Some(BenchError::Interrupted));
+    if healthy && state != StressState::Connecting {
+        let mut buf = Vec::new();
+        if DisconnectPacket::new().encode(&mut buf).is_ok() {
+            let _ = tx.write_all(&buf[..]).await;
+            packet_log!(traced, client_id = %client_id, "sent DISCONNECT");
+        }
+    }
     // Updating counter of the exiting tasks
-    stream.shutdown().await.unwrap();
+    let _ = tx.shutdown().await;
     registry.exited_tasks_inc();
-    registry.established_connection_decr();
-    return;
+    match &exit_error {
+        Some(e) => registry.task_failure_inc(e.reason()),
+        None => registry.finished_tasks_inc(),
+    }
+    if state == StressState::Connecting {
+        registry.ongoing_connection_decr();
+    } else {
+        registry.established_connection_decr();
+        registry.disconnect_inc(reason);
+    }
+    trace.finish(exit_error.map(|e| e.to_string()));
+}
+
+// publish_loops is the number of publishes a task sends during the test
+pub fn publish_loops(cfg: &config::Config) -> i32 {
+    cfg.duration * 1000 / cfg.think_time
+}
+
+// expected_duration estimates how long the tasks of the config will run,
+// including the random delays of the ramp-up.
+pub fn expected_duration(cfg: &config::Config) -> Duration {
+    Duration::from_millis(RAMP_UP_MILLS + CONNECT_JITTER_MILLS)
+        + Duration::from_secs(cfg.duration.max(0) as u64)
 }
 
 async fn retry<'a, F, T>(
     f: F,
     http_client: &'a Arc<MyClient>,
     cfg: &'a config::Config,
-    things_idx: usize,
+    things: &'a config::ThingsInfo,
     total: usize,
-) -> String
+) -> BenchResult<String>
 where
-    F: Fn(&'a Arc<MyClient>, &'a config::Config, usize) -> T + 'a,
-    T: std::future::Future<Output = String>,
+    F: Fn(&'a Arc<MyClient>, &'a config::Config, &'a config::ThingsInfo) -> T + 'a,
+    T: std::future::Future<Output = BenchResult<String>>,
 {
     let mut count = 0;
     loop {
-        let result = f(http_client, cfg, things_idx).await;
-        if result.eq("") {
-            count = count + 1;
-            if count > total {
-                return "".to_string();
+        match f(http_client, cfg, things).await {
+            Ok(result) => return Ok(result),
+            Err(e) => {
+                count += 1;
+                if count > total || !e.is_retryable() {
+                    return Err(e);
+                }
+                warn!(count, error = %e, "get things password failed, retrying");
+                shuffle_sleep(5000).await;
             }
-            println!(
-                "retrying due to error, get things password, count is {}",
-                count
-            );
-            shuffle_sleep(5000).await;
-        } else {
-            return result;
         }
     }
 }
 
-async fn connect_broker<'a>(
-    cfg: &'a config::Config,
-    things_idx: usize,
-    client_id: &'a str,
-    http_client: Arc<MyClient>,
-) -> std::result::Result<TcpStream, std::io::Error> {
-    println!("client id is {}", client_id);
-    let password = retry(get_things_password, &http_client, cfg, things_idx, 10).await;
-    if password.eq("") {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "server can't handle request, password is empty",
-        ));
+// read_device returns the device of the task, the devices of the files are
+// read off the runtime
+async fn read_device(cfg: &Arc<config::Config>, idx: usize) -> BenchResult<config::ThingsInfo> {
+    fn device(cfg: &config::Config, idx: usize) -> BenchResult<config::ThingsInfo> {
+        cfg.things_info
+            .device(idx)
+            .map(Cow::into_owned)
+            .map_err(|e| BenchError::Config(e.to_string()))
     }
+    if cfg.things_info.location(idx).1.is_none() {
+        return device(cfg, idx);
+    }
+    let cfg = cfg.clone();
+    tokio::task::spawn_blocking(move || device(&cfg, idx))
+        .await
+        .map_err(|e| BenchError::Config(e.to_string()))?
+}
 
+// pick_broker selects the broker of a connection randomly
+fn pick_broker(cfg: &config::Config) -> String {
     let mut broker_addr = cfg.broker_addr[0].clone();
     if cfg.broker_addr.len() > 1 {
         let num = rand::thread_rng().gen_range(0..cfg.broker_addr.len());
         broker_addr = cfg.broker_addr[num].clone()
     }
+    broker_addr
+}
+
+// new_breakdowns returns the per broker, tenant and info model entries the
+// connection reports to
+fn new_breakdowns(
+    registry: &stressing_registry::MetricRegistry,
+    things_info: &config::ThingsInfo,
+    broker_addr: &str,
+) -> Breakdowns {
+    let mut breakdowns = Breakdowns::default();
+    breakdowns.push(registry.breakdown(stressing_registry::BROKER, broker_addr));
+    breakdowns.push(registry.breakdown(stressing_registry::TENANT, &things_info.tenant_name));
+    breakdowns
+        .push(registry.breakdown(stressing_registry::INFO_MODEL, &things_info.info_model_name));
+    breakdowns
+}
+
+// connect_broker fetches the password, connects the broker and sends
+// CONNECT, the duration of every phase is observed by the registry.
+#[allow(clippy::too_many_arguments)]
+async fn connect_broker<'a>(
+    cfg: &'a config::Config,
+    things: &'a config::ThingsInfo,
+    client_id: &'a str,
+    broker_addr: &'a str,
+    http_client: Arc<MyClient>,
+    tls: Option<&TlsConnector>,
+    registry: &stressing_registry::MetricRegistry,
+    trace: &mut ConnectionTrace,
+    events: &ConnectionEvents,
+) -> BenchResult<Box<dyn BrokerStream>> {
+    debug!(client_id = %client_id, "fetching things password");
+    let dynamic_token = !cfg.dynamic_token.url.is_empty();
+    if dynamic_token {
+        events.emit(Event::TokenRequested);
+    }
+    let token_started = Instant::now();
+    let result = retry(get_things_password, &http_client, cfg, things, 10).await;
+    let token_elapsed = token_started.elapsed();
+    if dynamic_token {
+        registry.phase_observe(Phase::TokenFetch, token_elapsed);
+    }
+    let password = match result {
+        Ok(password) => password,
+        Err(e) => {
+            trace.span("token_fetch", token_started.elapsed(), Some(e.to_string()));
+            events.emit(Event::TokenFailed {
+                elapsed_ms: millis(token_elapsed),
+                error: e.to_string(),
+            });
+            return Err(e);
+        }
+    };
+    if dynamic_token {
+        trace.span("token_fetch", token_started.elapsed(), None);
+        events.emit(Event::TokenObtained {
+            elapsed_ms: millis(token_elapsed),
+        });
+    }
+
+    shuffle_sleep(CONNECT_JITTER_MILLS).await; // avoid the file descriptor was exhausted
+    let dns_started = Instant::now();
+    let addrs: Vec<SocketAddr> = match lookup_host(broker_addr).await {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => {
+            let e = BenchError::Dns(e.to_string());
+            warn!(client_id = %client_id, broker = %broker_addr, error = %e, "resolve broker failed");
+            trace.span("dns", dns_started.elapsed(), Some(e.to_string()));
+            return Err(e);
+        }
+    };
+    registry.phase_observe(Phase::Dns, dns_started.elapsed());
+    trace.span("dns", dns_started.elapsed(), None);
 
-    shuffle_sleep(30000).await; // avoid the file descriptor was exhausted
-    let mut stream = match TcpStream::connect(&broker_addr).await {
+    let connect_started = Instant::now();
+    let stream = match TcpStream::connect(&addrs[..]).await {
         Ok(stream) => stream,
         Err(e) => {
-            println!("connect {} error: {}", broker_addr, e);
+            let e = BenchError::from_connect(e);
+            warn!(client_id = %client_id, broker = %broker_addr, error = %e, "connect broker failed");
+            trace.span(
+                "tcp_connect",
+                connect_started.elapsed(),
+                Some(e.to_string()),
+            );
+            events.emit(Event::TcpFailed {
+                broker: broker_addr.to_string(),
+                elapsed_ms: millis(connect_started.elapsed()),
+                error: e.to_string(),
+            });
             return Err(e);
         }
     };
-    println!("broker {} was connected send connect packet", broker_addr);
+    let connect_elapsed = connect_started.elapsed();
+    registry.phase_observe(Phase::TcpConnect, connect_elapsed);
+    trace.span("tcp_connect", connect_started.elapsed(), None);
+    events.emit(Event::TcpConnected {
+        broker: broker_addr.to_string(),
+        elapsed_ms: millis(connect_elapsed),
+    });
+
+    let mut stream: Box<dyn BrokerStream> = match tls {
+        Some(connector) => {
+            let handshake_started = Instant::now();
+            let server_name = if cfg.tls.server_name.is_empty() {
+                broker_host(broker_addr)
+            } else {
+                &cfg.tls.server_name
+            };
+            match connector.connect(server_name, stream).await {
+                Ok(stream) => {
+                    registry.phase_observe(Phase::TlsHandshake, handshake_started.elapsed());
+                    trace.span("tls_handshake", handshake_started.elapsed(), None);
+                    Box::new(stream)
+                }
+                Err(e) => {
+                    let e = BenchError::Tls(e.to_string());
+                    warn!(client_id = %client_id, broker = %broker_addr, error = %e, "TLS handshake failed");
+                    trace.span(
+                        "tls_handshake",
+                        handshake_started.elapsed(),
+                        Some(e.to_string()),
+                    );
+                    return Err(e);
+                }
+            }
+        }
+        None => Box::new(stream),
+    };
+    debug!(client_id = %client_id, broker = %broker_addr, "broker was connected, sending CONNECT");
 
     let mut conn = ConnectPacket::new(client_id);
     conn.set_clean_session(true);
     conn.set_user_name(Option::Some(cfg.user_name.clone()));
     conn.set_password(Option::Some(password));
     let mut buf = Vec::new();
-    conn.encode(&mut buf).unwrap();
+    conn.encode(&mut buf)
+        .map_err(|e| BenchError::Protocol(e.to_string()))?;
     stream.write_all(&buf[..]).await?;
     Ok(stream)
 }
+
+// broker_host strips the port of the broker address, e.g. `[::1]:8883`
+fn broker_host(broker_addr: &str) -> &str {
+    let host = match broker_addr.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => broker_addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+// tls_connector builds the TLS connector shared by all connections, None if
+// TLS isn't enabled.
+pub fn tls_connector(tls: &config::Tls) -> BenchResult<Option<TlsConnector>> {
+    if !tls.enabled {
+        return Ok(None);
+    }
+    let mut builder = native_tls::TlsConnector::builder();
+    if !tls.ca_file.is_empty() {
+        let pem = std::fs::read(&tls.ca_file)
+            .map_err(|e| BenchError::Config(format!("read {} failed: {}", tls.ca_file, e)))?;
+        let certificate = native_tls::Certificate::from_pem(&pem)
+            .map_err(|e| BenchError::Config(format!("invalid CA {}: {}", tls.ca_file, e)))?;
+        builder.add_root_certificate(certificate);
+    }
+    if tls.insecure_skip_verify {
+        builder
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
+    let connector = builder
+        .build()
+        .map_err(|e| BenchError::Config(e.to_string()))?;
+    Ok(Some(connector.into()))
+}
+
 // shuffle_sleep sleep random mills millseconds
 async fn shuffle_sleep(max_mills: u64) {
     let mills = rand::thread_rng().gen_range(1..max_mills);
     time::sleep(Duration::from_millis(mills)).await;
 }
 
+// new_publish_packet returns None if the previous PUBLISH isn't acknowledged
 fn new_publish_packet(
-    client_id: &str,
     state: &StressState,
-    topic: &String,
-    payload: &Vec<u8>,
-) -> Result<PublishPacket> {
+    topic: &TopicName,
+    payload: &[u8],
+) -> Option<PublishPacket> {
     if state != &StressState::Published {
-        println!(
-            "Do nothing for client: {} as connection not build, current state is {:?}",
-            client_id, state
-        );
-        return Err(Error::new(ErrorKind::Other, "not ready"));
+        return None;
     }
 
     let packet = PublishPacket::new(
-        mqtt::TopicName::new(topic).unwrap(),
+        topic.clone(),
         QoSWithPacketIdentifier::Level1(1),
-        payload.clone(),
+        payload.to_vec(),
     );
-    return Ok(packet);
+    Some(packet)
 }
 
-fn get_topic(cfg: &config::Config, idx: usize, client_id: &str) -> String {
-    let context = cfg.to_context(idx, client_id);
-    render_template(&cfg.topic_template, &context)
+pub fn get_topic(cfg: &config::Config, things: &config::ThingsInfo, client_id: &str) -> String {
+    render_template(&cfg.topic_template, &things.to_context(client_id))
 }
 
-fn get_payload(cfg: &config::Config, idx: usize) -> Vec<u8> {
-    let tenant_name = &cfg.things_info[idx].tenant_name;
-    let res = cfg.things_payloads.get(tenant_name);
-    match res {
-        Some(payload) => {
-            return if cfg.is_payload_base64 {
-                println!("convert payload to base64");
-                return match general_purpose::STANDARD.decode(payload) {
-                    Ok(result) => {
-                        let mut vec = Vec::new();
-                        vec.extend_from_slice(&result);
-                        println!("payload length is {}", vec.len());
-                        vec
-                    }
-                    Err(e) => {
-                        println!("invalid base64 payload {}, error is {}", payload, e);
-                        panic!("payload isn't base64");
-                    }
-                };
-            } else {
-                Vec::from(payload.as_bytes())
-            };
-        }
-        None => {
-            panic!(
-                "things_idx{} client_id{} hasn't payload",
-                idx, cfg.things_info[idx].third_things_id
-            );
-        }
+pub fn get_payload(cfg: &config::Config, things: &config::ThingsInfo) -> BenchResult<Vec<u8>> {
+    let tenant_name = &things.tenant_name;
+    let payload = cfg.things_payloads.get(tenant_name).ok_or_else(|| {
+        BenchError::Config(format!(
+            "things {} of tenant {} hasn't payload",
+            things.third_things_id, tenant_name
+        ))
+    })?;
+    if !cfg.is_payload_base64 {
+        return Ok(Vec::from(payload.as_bytes()));
     }
+    general_purpose::STANDARD.decode(payload).map_err(|e| {
+        BenchError::Config(format!(
+            "payload of tenant {} isn't base64: {}",
+            tenant_name, e
+        ))
+    })
 }
 
 #[cfg(test)]
@@ -307,6 +648,17 @@ spec:
 "#;
 
     #[test]
+    fn test_broker_host() {
+        use crate::stressing::broker_host;
+
+        assert_eq!(broker_host("broker.local:8883"), "broker.local");
+        assert_eq!(broker_host("10.0.0.1:1883"), "10.0.0.1");
+        assert_eq!(broker_host("[::1]:8883"), "::1");
+        assert_eq!(broker_host("broker.local"), "broker.local");
+    }
+
+    #[test]
+    #[allow(clippy::len_zero)]
     fn test_get_payload() {
         use crate::config::spec_from_str;
         use crate::stressing::get_payload;
@@ -316,7 +668,8 @@ spec:
             crate::config::Spec::Publish(config) => config,
             _ => panic!("invalid config"),
         };
-        let payload = get_payload(&config, 0);
+        let things = config.things_info.device(0).unwrap();
+        let payload = get_payload(&config, &things).unwrap();
         println!("payload length is {}", payload.len());
         assert!(payload.len() != 0);
     }
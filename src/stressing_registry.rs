@@ -1,8 +1,12 @@
 use atomic_counter::{AtomicCounter, RelaxedCounter};
-use metrics::gauge;
+use hdrhistogram::Histogram;
+use metrics::{absolute_counter, gauge, histogram};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone)]
 pub enum TaskStatus {
@@ -16,38 +20,441 @@ impl fmt::Display for TaskStatus {
     }
 }
 
+// LoadStage is derived from the counters, it describes which part of the
+// test the tasks are currently in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadStage {
+    RampUp,
+    Steady,
+    Draining,
+    Finished,
+}
+
+impl fmt::Display for LoadStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            LoadStage::RampUp => "ramp-up",
+            LoadStage::Steady => "steady",
+            LoadStage::Draining => "draining",
+            LoadStage::Finished => "finished",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+// Phase is a measured step of the connection setup
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    // dynamic token HTTP call, including the retries
+    TokenFetch,
+    Dns,
+    TcpConnect,
+    TlsHandshake,
+    // CONNECT -> CONNACK
+    Connack,
+    // PUBLISH -> PUBACK of the first publish on the connection
+    FirstPuback,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 6] = [
+        Phase::TokenFetch,
+        Phase::Dns,
+        Phase::TcpConnect,
+        Phase::TlsHandshake,
+        Phase::Connack,
+        Phase::FirstPuback,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::TokenFetch => "token_fetch",
+            Phase::Dns => "dns",
+            Phase::TcpConnect => "tcp_connect",
+            Phase::TlsHandshake => "tls_handshake",
+            Phase::Connack => "connack",
+            Phase::FirstPuback => "first_puback",
+        }
+    }
+}
+
+// Summary is a latency histogram exported as a summary, by the pushers
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub name: &'static str,
+    // labels of the summary in addition to the registry labels
+    pub labels: Vec<(String, String)>,
+    pub latency: LatencySummary,
+}
+
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub name: String,
+    pub kind: MetricKind,
+    pub value: f64,
+    // labels of the sample in addition to the registry labels
+    pub labels: Vec<(String, String)>,
+}
+
+// LabeledCounter counts the occurrences of a label value, e.g. the reasons of
+// the failed tasks. The label values are a small fixed set.
+#[derive(Debug)]
+struct LabeledCounter {
+    label: &'static str,
+    counts: Mutex<BTreeMap<&'static str, usize>>,
+}
+
+impl LabeledCounter {
+    fn new(label: &'static str) -> LabeledCounter {
+        LabeledCounter {
+            label,
+            counts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn inc(&self, value: &'static str) {
+        *self.counts.lock().unwrap().entry(value).or_default() += 1;
+    }
+
+    fn absorb(&self, other: &LabeledCounter) {
+        let other = other.counts.lock().unwrap().clone();
+        let mut counts = self.counts.lock().unwrap();
+        for (value, count) in other {
+            *counts.entry(value).or_default() += count;
+        }
+    }
+
+    fn snapshot(&self) -> BTreeMap<String, usize> {
+        self.counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(value, count)| (value.to_string(), *count))
+            .collect()
+    }
+
+    fn samples(&self, name: &str) -> Vec<Sample> {
+        self.counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(value, count)| Sample {
+                name: name.to_string(),
+                kind: MetricKind::Counter,
+                value: *count as f64,
+                labels: vec![(self.label.to_string(), value.to_string())],
+            })
+            .collect()
+    }
+}
+
+// Dimensions of the breakdowns, the dimension is both the metric name prefix
+// and the label name, e.g. `broker_acks{broker="10.0.0.1:1883"}`
+pub const BROKER: &str = "broker";
+pub const TENANT: &str = "tenant";
+pub const INFO_MODEL: &str = "info_model";
+
+// Key of the breakdown entry collecting the keys beyond the cardinality limit
+pub const OTHER_KEY: &str = "other";
+
+pub const DEFAULT_MAX_BREAKDOWN_KEYS: usize = 100;
+
+// Labels set by the registry and the exporters
+pub const HOST: &str = "host";
+pub const TASK_NAME: &str = "task_name";
+pub const REASON: &str = "reason";
+pub const CODE: &str = "code";
+pub const PHASE: &str = "phase";
+pub const QUANTILE: &str = "quantile";
+
+// Every label name of the exported metrics, the metaData labels can't
+// override them
+pub const LABELS: [&str; 9] = [
+    HOST, TASK_NAME, BROKER, TENANT, INFO_MODEL, REASON, CODE, PHASE, QUANTILE,
+];
+
+// BreakdownEntry holds the counters of a single broker, tenant or info model
+#[derive(Debug)]
+pub struct BreakdownEntry {
+    dimension: &'static str,
+    key: String,
+    connects: RelaxedCounter,
+    connect_failures: RelaxedCounter,
+    publishes: RelaxedCounter,
+    acks: RelaxedCounter,
+    ack_latency: Mutex<Histogram<u64>>,
+    // labels of the latency histogram, registry labels plus the dimension
+    labels: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BreakdownSnapshot {
+    pub dimension: String,
+    pub key: String,
+    pub connects: usize,
+    pub connect_failures: usize,
+    pub publishes: usize,
+    pub acks: usize,
+    pub ack_latency: LatencySummary,
+}
+
+impl BreakdownEntry {
+    fn snapshot(&self) -> BreakdownSnapshot {
+        BreakdownSnapshot {
+            dimension: self.dimension.to_string(),
+            key: self.key.clone(),
+            connects: self.connects.get(),
+            connect_failures: self.connect_failures.get(),
+            publishes: self.publishes.get(),
+            acks: self.acks.get(),
+            ack_latency: LatencySummary::from_histogram(&self.ack_latency.lock().unwrap()),
+        }
+    }
+}
+
+// Breakdowns are the breakdown entries a single connection reports to
+#[derive(Debug, Default)]
+pub struct Breakdowns(Vec<Arc<BreakdownEntry>>);
+
+impl Breakdowns {
+    pub fn push(&mut self, entry: Arc<BreakdownEntry>) {
+        self.0.push(entry);
+    }
+
+    pub fn connects_inc(&self) {
+        for e in self.0.iter() {
+            e.connects.inc();
+        }
+    }
+
+    pub fn connect_failures_inc(&self) {
+        for e in self.0.iter() {
+            e.connect_failures.inc();
+        }
+    }
+
+    pub fn publishes_inc(&self) {
+        for e in self.0.iter() {
+            e.publishes.inc();
+        }
+    }
+
+    pub fn acks_inc(&self) {
+        for e in self.0.iter() {
+            e.acks.inc();
+        }
+    }
+
+    pub fn ack_latency_observe(&self, latency: Duration) {
+        let micros = latency.as_micros().max(1) as u64;
+        for e in self.0.iter() {
+            e.ack_latency.lock().unwrap().saturating_record(micros);
+            histogram!(
+                format!("{}_ack_latency_seconds", e.dimension),
+                latency.as_secs_f64(),
+                &e.labels
+            );
+        }
+    }
+}
+
+// Snapshot is a point-in-time copy of the registry counters.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub task_name: String,
+    pub labels: Vec<(String, String)>,
+    pub task_status: TaskStatus,
+    pub stage: LoadStage,
+    pub elapsed: Duration,
+    pub planned_tasks: usize,
+    pub target_publishes: usize,
+    pub accepted_connections: usize,
+    pub running_tasks: usize,
+    pub exited_tasks: usize,
+    pub finished_tasks: usize,
+    pub invalid_pubacks: usize,
+    pub timeout_pubacks: usize,
+    pub sent_packets: usize,
+    pub publish_packets: usize,
+    pub established_connection: u32,
+    pub ongoing_connection: u32,
+    pub ack_latency: LatencySummary,
+    pub breakdowns: Vec<BreakdownSnapshot>,
+    // exited tasks which didn't finish, by the reason category
+    pub task_failures: BTreeMap<String, usize>,
+    // connection setup phases which were observed, by the phase name
+    pub phases: BTreeMap<String, LatencySummary>,
+    // received CONNACKs by the return code
+    pub connack_codes: BTreeMap<String, usize>,
+    // closed connections which were established, by the reason category
+    pub disconnects: BTreeMap<String, usize>,
+}
+
+// LatencySummary values are in milliseconds.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl LatencySummary {
+    fn from_histogram(h: &Histogram<u64>) -> LatencySummary {
+        if h.is_empty() {
+            return LatencySummary::default();
+        }
+        LatencySummary {
+            count: h.len(),
+            mean: h.mean() / 1000.0,
+            p50: h.value_at_quantile(0.5) as f64 / 1000.0,
+            p90: h.value_at_quantile(0.9) as f64 / 1000.0,
+            p99: h.value_at_quantile(0.99) as f64 / 1000.0,
+            max: h.max() as f64 / 1000.0,
+        }
+    }
+}
+
+// new_latency_histogram tracks latencies from 1 microsecond to 1 hour
+fn new_latency_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, 3_600_000_000, 3).unwrap()
+}
+
 #[derive(Debug)]
 pub struct MetricRegistry {
     running_tasks: RelaxedCounter,
     exited_tasks: RelaxedCounter,
+    finished_tasks: RelaxedCounter,
     invalid_pubacks: RelaxedCounter,
     timeout_pubacks: RelaxedCounter,
+    sent_packets: RelaxedCounter,
     publish_packets: RelaxedCounter,
     established_connection: AtomicU32,
     ongoing_connection: AtomicU32,
+    planned_tasks: RelaxedCounter,
+    target_publishes: RelaxedCounter,
+    accepted_connections: RelaxedCounter,
+    task_failures: LabeledCounter,
+    connack_codes: LabeledCounter,
+    disconnects: LabeledCounter,
+    // latency between sending PUBLISH and receiving PUBACK, in microseconds
+    ack_latency: Mutex<Histogram<u64>>,
+    // durations of the connection setup phases, indexed like Phase::ALL
+    phases: Vec<Mutex<Histogram<u64>>>,
+    started_at: Mutex<Option<Instant>>,
+    // labels attached to all exported metrics
+    labels: Vec<(String, String)>,
+    breakdowns: Mutex<HashMap<(&'static str, String), Arc<BreakdownEntry>>>,
+    // max distinct keys of a breakdown dimension, guards the cardinality
+    max_breakdown_keys: usize,
     task_name: String,
     task_status: Mutex<TaskStatus>,
 }
 
 impl MetricRegistry {
     pub fn new(task_name: String) -> MetricRegistry {
-        return MetricRegistry {
+        MetricRegistry {
             running_tasks: RelaxedCounter::new(0),
             exited_tasks: RelaxedCounter::new(0),
+            finished_tasks: RelaxedCounter::new(0),
             invalid_pubacks: RelaxedCounter::new(0),
             timeout_pubacks: RelaxedCounter::new(0),
+            sent_packets: RelaxedCounter::new(0),
             publish_packets: RelaxedCounter::new(0),
             established_connection: AtomicU32::new(0),
             ongoing_connection: AtomicU32::new(0),
-            task_name: task_name,
+            planned_tasks: RelaxedCounter::new(0),
+            target_publishes: RelaxedCounter::new(0),
+            accepted_connections: RelaxedCounter::new(0),
+            task_failures: LabeledCounter::new(REASON),
+            connack_codes: LabeledCounter::new(CODE),
+            disconnects: LabeledCounter::new(REASON),
+            ack_latency: Mutex::new(new_latency_histogram()),
+            phases: Phase::ALL
+                .iter()
+                .map(|_| Mutex::new(new_latency_histogram()))
+                .collect(),
+            started_at: Mutex::new(None),
+            labels: vec![],
+            breakdowns: Mutex::new(HashMap::new()),
+            max_breakdown_keys: DEFAULT_MAX_BREAKDOWN_KEYS,
+            task_name,
             task_status: Mutex::new(TaskStatus::Stop),
+        }
+    }
+    pub fn with_labels(mut self, labels: Vec<(String, String)>) -> MetricRegistry {
+        self.labels = labels;
+        self
+    }
+
+    pub fn with_max_breakdown_keys(mut self, max_breakdown_keys: usize) -> MetricRegistry {
+        self.max_breakdown_keys = max_breakdown_keys;
+        self
+    }
+
+    // breakdown returns the entry of the key in the dimension, keys beyond
+    // the cardinality limit share the `other` entry.
+    pub fn breakdown(
+        self: &MetricRegistry,
+        dimension: &'static str,
+        key: &str,
+    ) -> Arc<BreakdownEntry> {
+        let mut breakdowns = self.breakdowns.lock().unwrap();
+        if let Some(entry) = breakdowns.get(&(dimension, key.to_string())) {
+            return entry.clone();
+        }
+        let keys = breakdowns.keys().filter(|(d, _)| *d == dimension).count();
+        let key = if keys >= self.max_breakdown_keys {
+            OTHER_KEY
+        } else {
+            key
         };
+        let mut labels = self.labels();
+        labels.push((dimension.to_string(), key.to_string()));
+        breakdowns
+            .entry((dimension, key.to_string()))
+            .or_insert_with(|| {
+                Arc::new(BreakdownEntry {
+                    dimension,
+                    key: key.to_string(),
+                    connects: RelaxedCounter::new(0),
+                    connect_failures: RelaxedCounter::new(0),
+                    publishes: RelaxedCounter::new(0),
+                    acks: RelaxedCounter::new(0),
+                    ack_latency: Mutex::new(new_latency_histogram()),
+                    labels,
+                })
+            })
+            .clone()
+    }
+
+    fn breakdown_snapshots(self: &MetricRegistry) -> Vec<BreakdownSnapshot> {
+        let mut snapshots: Vec<BreakdownSnapshot> = self
+            .breakdowns
+            .lock()
+            .unwrap()
+            .values()
+            .map(|e| e.snapshot())
+            .collect();
+        snapshots.sort_by(|a, b| (&a.dimension, &a.key).cmp(&(&b.dimension, &b.key)));
+        snapshots
     }
+
     pub fn start_task(self: &MetricRegistry) {
         self.task_status
             .lock()
             .unwrap()
             .clone_from(&TaskStatus::Run);
+        self.started_at.lock().unwrap().replace(Instant::now());
     }
 
     pub fn task_stopped(self: &MetricRegistry) {
@@ -59,6 +466,18 @@ impl MetricRegistry {
         self.ongoing_connection_reset();
     }
 
+    pub fn planned_tasks_add(self: &MetricRegistry, count: usize) {
+        self.planned_tasks.add(count);
+    }
+
+    pub fn target_publishes_add(self: &MetricRegistry, count: usize) {
+        self.target_publishes.add(count);
+    }
+
+    pub fn accepted_connections_inc(self: &MetricRegistry) {
+        self.accepted_connections.inc();
+    }
+
     pub fn running_tasks_inc(self: &MetricRegistry) {
         self.running_tasks.inc();
     }
@@ -67,6 +486,25 @@ impl MetricRegistry {
         self.exited_tasks.inc();
     }
 
+    pub fn finished_tasks_inc(self: &MetricRegistry) {
+        self.finished_tasks.inc();
+    }
+
+    // task_failure_inc counts a task exited early by the reason category
+    pub fn task_failure_inc(self: &MetricRegistry, reason: &'static str) {
+        self.task_failures.inc(reason);
+    }
+
+    pub fn connack_code_inc(self: &MetricRegistry, code: &'static str) {
+        self.connack_codes.inc(code);
+    }
+
+    // disconnect_inc counts an established connection closed by the reason
+    // category, `finished` if the task closed it after all publishes.
+    pub fn disconnect_inc(self: &MetricRegistry, reason: &'static str) {
+        self.disconnects.inc(reason);
+    }
+
     pub fn invalid_pubacks_inc(self: &MetricRegistry) {
         self.invalid_pubacks.inc();
     }
@@ -99,52 +537,434 @@ impl MetricRegistry {
         self.established_connection.fetch_min(0, Ordering::Relaxed);
     }
 
+    pub fn sent_packets_inc(self: &MetricRegistry) {
+        self.sent_packets.inc();
+    }
+
     pub fn publish_packets_inc(self: &MetricRegistry) {
         self.publish_packets.inc();
     }
 
-    pub fn update(self: &MetricRegistry, labels: &[(String, String); 1]) {
-        let mut new_labels = vec![];
-        for label in labels.iter() {
-            new_labels.push((label.0.clone(), label.1.clone()));
+    pub fn ack_latency_observe(self: &MetricRegistry, latency: Duration) {
+        let micros = latency.as_micros().max(1) as u64;
+        self.ack_latency.lock().unwrap().saturating_record(micros);
+        histogram!("ack_latency_seconds", latency.as_secs_f64(), &self.labels());
+    }
+
+    pub fn phase_observe(self: &MetricRegistry, phase: Phase, elapsed: Duration) {
+        let micros = elapsed.as_micros().max(1) as u64;
+        self.phases[phase as usize]
+            .lock()
+            .unwrap()
+            .saturating_record(micros);
+        let mut labels = self.labels();
+        labels.push((PHASE.to_string(), phase.name().to_string()));
+        histogram!("connect_phase_seconds", elapsed.as_secs_f64(), &labels);
+    }
+
+    fn phase_summaries(self: &MetricRegistry) -> Vec<(Phase, LatencySummary)> {
+        Phase::ALL
+            .iter()
+            .map(|phase| {
+                let h = self.phases[*phase as usize].lock().unwrap();
+                (*phase, LatencySummary::from_histogram(&h))
+            })
+            .filter(|(_, latency)| latency.count > 0)
+            .collect()
+    }
+
+    // summaries returns the latency histograms which are pushed as summaries
+    pub fn summaries(self: &MetricRegistry) -> Vec<Summary> {
+        let mut summaries = vec![Summary {
+            name: "ack_latency_seconds",
+            labels: vec![],
+            latency: LatencySummary::from_histogram(&self.ack_latency.lock().unwrap()),
+        }];
+        for (phase, latency) in self.phase_summaries() {
+            summaries.push(Summary {
+                name: "connect_phase_seconds",
+                labels: vec![(PHASE.to_string(), phase.name().to_string())],
+                latency,
+            });
         }
+        summaries
+    }
 
-        new_labels.push(("task_name".to_string(), self.task_name.clone()));
-        new_labels.push((
-            "task_status".to_string(),
-            self.task_status.lock().unwrap().to_string(),
-        ));
+    pub fn snapshot(self: &MetricRegistry) -> Snapshot {
+        let task_status = self.task_status.lock().unwrap().clone();
+        let elapsed = match *self.started_at.lock().unwrap() {
+            Some(started_at) => started_at.elapsed(),
+            None => Duration::ZERO,
+        };
+        let ack_latency = LatencySummary::from_histogram(&self.ack_latency.lock().unwrap());
+        let mut snapshot = Snapshot {
+            task_name: self.task_name.clone(),
+            labels: self.labels.clone(),
+            task_status,
+            stage: LoadStage::RampUp,
+            elapsed,
+            planned_tasks: self.planned_tasks.get(),
+            target_publishes: self.target_publishes.get(),
+            accepted_connections: self.accepted_connections.get(),
+            running_tasks: self.running_tasks.get(),
+            exited_tasks: self.exited_tasks.get(),
+            finished_tasks: self.finished_tasks.get(),
+            invalid_pubacks: self.invalid_pubacks.get(),
+            timeout_pubacks: self.timeout_pubacks.get(),
+            sent_packets: self.sent_packets.get(),
+            publish_packets: self.publish_packets.get(),
+            established_connection: self.established_connection.load(Ordering::Relaxed),
+            ongoing_connection: self.ongoing_connection.load(Ordering::Relaxed),
+            ack_latency,
+            breakdowns: self.breakdown_snapshots(),
+            task_failures: self.task_failures.snapshot(),
+            phases: self
+                .phase_summaries()
+                .into_iter()
+                .map(|(phase, latency)| (phase.name().to_string(), latency))
+                .collect(),
+            connack_codes: self.connack_codes.snapshot(),
+            disconnects: self.disconnects.snapshot(),
+        };
+        snapshot.stage = snapshot.load_stage();
+        snapshot
+    }
 
-        gauge!(
-            "running_tasks",
-            self.running_tasks.get() as f64,
-            &new_labels
-        );
-        gauge!("exited_tasks", self.exited_tasks.get() as f64, &new_labels);
-        gauge!(
-            "invalid_pubacks",
-            self.invalid_pubacks.get() as f64,
-            &new_labels
-        );
-        gauge!(
-            "timeout_pubacks",
-            self.timeout_pubacks.get() as f64,
-            &new_labels
+    // absorb adds the counters and the latencies of the other registry, e.g.
+    // to combine the registries of the specs of a plan. The breakdowns stay
+    // with the other registry.
+    pub fn absorb(self: &MetricRegistry, other: &MetricRegistry) {
+        for (counter, other) in [
+            (&self.running_tasks, &other.running_tasks),
+            (&self.exited_tasks, &other.exited_tasks),
+            (&self.finished_tasks, &other.finished_tasks),
+            (&self.invalid_pubacks, &other.invalid_pubacks),
+            (&self.timeout_pubacks, &other.timeout_pubacks),
+            (&self.sent_packets, &other.sent_packets),
+            (&self.publish_packets, &other.publish_packets),
+            (&self.planned_tasks, &other.planned_tasks),
+            (&self.target_publishes, &other.target_publishes),
+            (&self.accepted_connections, &other.accepted_connections),
+        ] {
+            counter.add(other.get());
+        }
+        self.task_failures.absorb(&other.task_failures);
+        self.connack_codes.absorb(&other.connack_codes);
+        self.disconnects.absorb(&other.disconnects);
+        // The histograms share the bounds, adding them can't fail
+        let ack_latency = other.ack_latency.lock().unwrap().clone();
+        let _ = self.ack_latency.lock().unwrap().add(&ack_latency);
+        for (phase, other) in self.phases.iter().zip(&other.phases) {
+            let other = other.lock().unwrap().clone();
+            let _ = phase.lock().unwrap().add(&other);
+        }
+        // The keys beyond the limit of this registry are merged into the
+        // other key, they're added in order so the kept keys don't depend on
+        // the hashing
+        let mut entries: Vec<Arc<BreakdownEntry>> =
+            other.breakdowns.lock().unwrap().values().cloned().collect();
+        entries.sort_by(|a, b| (a.dimension, &a.key).cmp(&(b.dimension, &b.key)));
+        for other in entries {
+            let entry = self.breakdown(other.dimension, &other.key);
+            for (counter, other) in [
+                (&entry.connects, &other.connects),
+                (&entry.connect_failures, &other.connect_failures),
+                (&entry.publishes, &other.publishes),
+                (&entry.acks, &other.acks),
+            ] {
+                counter.add(other.get());
+            }
+            let ack_latency = other.ack_latency.lock().unwrap().clone();
+            let _ = entry.ack_latency.lock().unwrap().add(&ack_latency);
+        }
+    }
+
+    // labels are the labels of the registry and the task name, they're
+    // attached to the samples of the registry. The task status is a gauge
+    // rather than a label, a label would start new series of the counters
+    // when it changes.
+    pub fn labels(self: &MetricRegistry) -> Vec<(String, String)> {
+        let mut labels = self.labels.clone();
+        labels.push((TASK_NAME.to_string(), self.task_name.clone()));
+        labels
+    }
+
+    // samples returns the current value of every counter and gauge
+    pub fn samples(self: &MetricRegistry) -> Vec<Sample> {
+        let counter = |name: &str, value: usize| Sample {
+            name: name.to_string(),
+            kind: MetricKind::Counter,
+            value: value as f64,
+            labels: vec![],
+        };
+        let gauge = |name: &str, value: f64| Sample {
+            name: name.to_string(),
+            kind: MetricKind::Gauge,
+            value,
+            labels: vec![],
+        };
+        let mut samples = vec![
+            // Totals only ever increase, they are exported as counters
+            counter("running_tasks", self.running_tasks.get()),
+            counter("exited_tasks", self.exited_tasks.get()),
+            counter("finished_tasks", self.finished_tasks.get()),
+            counter("accepted_connections", self.accepted_connections.get()),
+            counter("invalid_pubacks", self.invalid_pubacks.get()),
+            counter("timeout_pubacks", self.timeout_pubacks.get()),
+            counter("sent_packets", self.sent_packets.get()),
+            counter("publish_packets", self.publish_packets.get()),
+            gauge("planned_tasks", self.planned_tasks.get() as f64),
+            gauge(
+                "established_connection",
+                self.established_connection.load(Ordering::Relaxed) as f64,
+            ),
+            gauge(
+                "ongoing_connection",
+                self.ongoing_connection.load(Ordering::Relaxed) as f64,
+            ),
+            // 1 while the tasks run, 0 once they stopped
+            gauge(
+                "task_status",
+                match *self.task_status.lock().unwrap() {
+                    TaskStatus::Run => 1.0,
+                    TaskStatus::Stop => 0.0,
+                },
+            ),
+        ];
+        samples.extend(self.task_failures.samples("task_failures"));
+        samples.extend(self.connack_codes.samples("connack_codes"));
+        samples.extend(self.disconnects.samples("disconnects"));
+        for b in self.breakdown_snapshots() {
+            for (name, value) in [
+                ("connects", b.connects),
+                ("connect_failures", b.connect_failures),
+                ("publishes", b.publishes),
+                ("acks", b.acks),
+            ] {
+                samples.push(Sample {
+                    name: format!("{}_{}", b.dimension, name),
+                    kind: MetricKind::Counter,
+                    value: value as f64,
+                    labels: vec![(b.dimension.clone(), b.key.clone())],
+                });
+            }
+        }
+        samples
+    }
+
+    pub fn update(self: &MetricRegistry) {
+        let labels = self.labels();
+        for sample in self.samples() {
+            let mut sample_labels = labels.clone();
+            sample_labels.extend(sample.labels);
+            match sample.kind {
+                MetricKind::Counter => {
+                    absolute_counter!(sample.name, sample.value as u64, &sample_labels)
+                }
+                MetricKind::Gauge => gauge!(sample.name, sample.value, &sample_labels),
+            }
+        }
+    }
+}
+
+impl Snapshot {
+    // failed_tasks are the tasks exited without finishing the test normally
+    pub fn failed_tasks(&self) -> usize {
+        self.exited_tasks.saturating_sub(self.finished_tasks)
+    }
+
+    fn load_stage(&self) -> LoadStage {
+        if let TaskStatus::Stop = self.task_status {
+            return LoadStage::Finished;
+        }
+        let settled = self.established_connection as usize + self.exited_tasks;
+        if settled < self.planned_tasks {
+            LoadStage::RampUp
+        } else if self.finished_tasks > 0 {
+            LoadStage::Draining
+        } else {
+            LoadStage::Steady
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use atomic_counter::AtomicCounter;
+
+    use super::{
+        BreakdownSnapshot, Breakdowns, LoadStage, MetricRegistry, Phase, BROKER, OTHER_KEY, TENANT,
+    };
+
+    #[test]
+    fn test_load_stage() {
+        let reg = MetricRegistry::new("task".to_string());
+        assert_eq!(reg.snapshot().stage, LoadStage::Finished);
+
+        reg.start_task();
+        reg.planned_tasks_add(2);
+        reg.established_connection_inc();
+        assert_eq!(reg.snapshot().stage, LoadStage::RampUp);
+
+        reg.exited_tasks_inc();
+        assert_eq!(reg.snapshot().stage, LoadStage::Steady);
+
+        reg.established_connection_decr();
+        reg.exited_tasks_inc();
+        reg.finished_tasks_inc();
+        let snapshot = reg.snapshot();
+        assert_eq!(snapshot.stage, LoadStage::Draining);
+        assert_eq!(snapshot.failed_tasks(), 1);
+
+        reg.task_stopped();
+        assert_eq!(reg.snapshot().stage, LoadStage::Finished);
+    }
+
+    #[test]
+    fn test_phase_summaries() {
+        let reg = MetricRegistry::new("task".to_string());
+        reg.phase_observe(Phase::Dns, Duration::from_millis(2));
+        reg.phase_observe(Phase::Connack, Duration::from_millis(20));
+        reg.phase_observe(Phase::Connack, Duration::from_millis(40));
+
+        let phases = reg.snapshot().phases;
+        assert_eq!(phases.keys().collect::<Vec<_>>(), vec!["connack", "dns"]);
+        assert_eq!(phases["connack"].count, 2);
+        assert!((phases["dns"].p50 - 2.0).abs() < 0.01);
+
+        let summaries = reg.summaries();
+        assert_eq!(summaries.len(), 3);
+        assert_eq!(summaries[0].name, "ack_latency_seconds");
+        assert_eq!(summaries[1].name, "connect_phase_seconds");
+        assert_eq!(
+            summaries[1].labels,
+            vec![("phase".to_string(), "dns".to_string())]
         );
-        gauge!(
-            "publish_packets",
-            self.publish_packets.get() as f64,
-            &new_labels
+    }
+
+    #[test]
+    fn test_labeled_counters() {
+        let reg = MetricRegistry::new("task".to_string());
+        reg.connack_code_inc("accepted");
+        reg.connack_code_inc("bad_username_or_password");
+        reg.connack_code_inc("bad_username_or_password");
+        reg.disconnect_inc("eof");
+        reg.task_failure_inc("connack");
+
+        let snapshot = reg.snapshot();
+        assert_eq!(snapshot.connack_codes["bad_username_or_password"], 2);
+        assert_eq!(snapshot.connack_codes["accepted"], 1);
+        assert_eq!(snapshot.disconnects["eof"], 1);
+        assert_eq!(snapshot.task_failures["connack"], 1);
+
+        let sample = reg
+            .samples()
+            .into_iter()
+            .find(|s| s.name == "connack_codes" && s.value == 2.0)
+            .unwrap();
+        assert_eq!(
+            sample.labels,
+            vec![("code".to_string(), "bad_username_or_password".to_string())]
         );
-        gauge!(
-            "established_connection",
-            self.established_connection.load(Ordering::Relaxed) as f64,
-            &new_labels
+    }
+
+    #[test]
+    fn test_breakdown_cardinality_guard() {
+        let reg = MetricRegistry::new("task".to_string()).with_max_breakdown_keys(2);
+        let mut breakdowns = Breakdowns::default();
+        breakdowns.push(reg.breakdown(BROKER, "10.0.0.1:1883"));
+        breakdowns.push(reg.breakdown(TENANT, "google"));
+        breakdowns.connects_inc();
+        breakdowns.acks_inc();
+        breakdowns.ack_latency_observe(Duration::from_millis(3));
+        reg.breakdown(BROKER, "10.0.0.2:1883")
+            .connect_failures
+            .inc();
+        reg.breakdown(BROKER, "10.0.0.3:1883")
+            .connect_failures
+            .inc();
+        reg.breakdown(BROKER, "10.0.0.4:1883")
+            .connect_failures
+            .inc();
+        // existing keys are still found after the limit was reached
+        reg.breakdown(BROKER, "10.0.0.1:1883").connects.inc();
+
+        let brokers: Vec<BreakdownSnapshot> = reg
+            .snapshot()
+            .breakdowns
+            .into_iter()
+            .filter(|b| b.dimension == BROKER)
+            .collect();
+        assert_eq!(brokers.len(), 3);
+        assert_eq!(brokers[0].key, "10.0.0.1:1883");
+        assert_eq!(brokers[0].connects, 2);
+        assert_eq!(brokers[0].acks, 1);
+        assert_eq!(brokers[0].ack_latency.count, 1);
+        assert_eq!(brokers[2].key, OTHER_KEY);
+        assert_eq!(brokers[2].connect_failures, 2);
+
+        let sample = reg
+            .samples()
+            .into_iter()
+            .find(|s| s.name == "tenant_acks")
+            .unwrap();
+        assert_eq!(sample.value, 1.0);
+        assert_eq!(
+            sample.labels,
+            vec![("tenant".to_string(), "google".to_string())]
         );
-        gauge!(
-            "ongoing_connection",
-            self.ongoing_connection.load(Ordering::Relaxed) as f64,
-            &new_labels
-        )
+    }
+
+    #[test]
+    fn test_absorb() {
+        let publish = MetricRegistry::new("publish".to_string());
+        publish.planned_tasks_add(2);
+        publish.publish_packets_inc();
+        publish.ack_latency_observe(Duration::from_millis(10));
+        publish.task_failure_inc("eof");
+        let storm = MetricRegistry::new("storm".to_string());
+        storm.planned_tasks_add(3);
+        storm.ack_latency_observe(Duration::from_millis(30));
+        storm.phase_observe(Phase::Dns, Duration::from_millis(2));
+        storm.task_failure_inc("eof");
+        for (registry, broker) in [(&publish, "10.0.0.1:1883"), (&storm, "10.0.0.2:1883")] {
+            let mut breakdowns = Breakdowns::default();
+            breakdowns.push(registry.breakdown(BROKER, broker));
+            breakdowns.connects_inc();
+            breakdowns.ack_latency_observe(Duration::from_millis(20));
+        }
+
+        let combined = MetricRegistry::new("publish+storm".to_string()).with_max_breakdown_keys(1);
+        combined.absorb(&publish);
+        combined.absorb(&storm);
+        let snapshot = combined.snapshot();
+        assert_eq!(snapshot.planned_tasks, 5);
+        assert_eq!(snapshot.publish_packets, 1);
+        assert_eq!(snapshot.ack_latency.count, 2);
+        assert!(snapshot.ack_latency.max >= 30.0);
+        assert_eq!(snapshot.phases["dns"].count, 1);
+        assert_eq!(snapshot.task_failures["eof"], 2);
+        // The second broker is beyond the limit of the combined registry
+        let keys: Vec<(&str, usize, u64)> = snapshot
+            .breakdowns
+            .iter()
+            .map(|b| (b.key.as_str(), b.connects, b.ack_latency.count))
+            .collect();
+        assert_eq!(keys, vec![("10.0.0.1:1883", 1, 1), (OTHER_KEY, 1, 1)]);
+    }
+
+    #[test]
+    fn test_ack_latency_summary() {
+        let reg = MetricRegistry::new("task".to_string());
+        for i in 1..=100 {
+            reg.ack_latency_observe(Duration::from_millis(i));
+        }
+        let latency = reg.snapshot().ack_latency;
+        assert_eq!(latency.count, 100);
+        assert!((latency.p50 - 50.0).abs() < 0.1);
+        assert!((latency.p99 - 99.0).abs() < 0.1);
+        assert!((latency.max - 100.0).abs() < 0.1);
     }
 }
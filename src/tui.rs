@@ -0,0 +1,213 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::{task::JoinHandle, time};
+
+use crate::report;
+use crate::stressing_registry::{MetricRegistry, Snapshot, TaskStatus};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(1000);
+
+// Number of refresh intervals kept for the latency sparkline
+const HISTORY_LEN: usize = 60;
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// Dashboard renders the live state of a MetricRegistry to the terminal
+pub struct Dashboard {
+    registry: Arc<MetricRegistry>,
+    expected: Duration,
+    previous: Option<Snapshot>,
+    latency_history: VecDeque<f64>,
+}
+
+// start spawns a task refreshing the dashboard every second, the task ends
+// after the registry was stopped and the last frame was drawn.
+pub fn start(registry: Arc<MetricRegistry>, expected: Duration) -> JoinHandle<()> {
+    let mut dashboard = Dashboard::new(registry, expected);
+    tokio::spawn(async move {
+        let mut refresh = time::interval(REFRESH_INTERVAL);
+        loop {
+            refresh.tick().await;
+            let snapshot = dashboard.registry.snapshot();
+            let frame = dashboard.frame(snapshot.clone());
+            let mut stdout = std::io::stdout().lock();
+            // Move the cursor home and clear the screen before drawing
+            let _ = write!(stdout, "\x1b[H\x1b[2J{}", frame);
+            let _ = stdout.flush();
+            if let TaskStatus::Stop = snapshot.task_status {
+                break;
+            }
+        }
+    })
+}
+
+impl Dashboard {
+    pub fn new(registry: Arc<MetricRegistry>, expected: Duration) -> Dashboard {
+        Dashboard {
+            registry,
+            expected,
+            previous: None,
+            latency_history: VecDeque::with_capacity(HISTORY_LEN),
+        }
+    }
+
+    // frame renders the snapshot, rates are calculated against the snapshot
+    // of the previous frame.
+    fn frame(&mut self, snapshot: Snapshot) -> String {
+        let (send_rate, ack_rate, interval_latency) = match &self.previous {
+            Some(prev) => {
+                let secs = (snapshot.elapsed.as_secs_f64() - prev.elapsed.as_secs_f64()).max(0.001);
+                let send_rate = (snapshot.sent_packets - prev.sent_packets) as f64 / secs;
+                let ack_rate = (snapshot.publish_packets - prev.publish_packets) as f64 / secs;
+                let acks = snapshot.ack_latency.count - prev.ack_latency.count;
+                let latency = if acks == 0 {
+                    0.0
+                } else {
+                    (snapshot.ack_latency.mean * snapshot.ack_latency.count as f64
+                        - prev.ack_latency.mean * prev.ack_latency.count as f64)
+                        / acks as f64
+                };
+                (send_rate, ack_rate, latency.max(0.0))
+            }
+            None => (0.0, 0.0, 0.0),
+        };
+        if self.latency_history.len() == HISTORY_LEN {
+            self.latency_history.pop_front();
+        }
+        self.latency_history.push_back(interval_latency);
+
+        let history: Vec<f64> = self.latency_history.iter().copied().collect();
+        let frame = render(
+            &snapshot,
+            self.expected,
+            send_rate,
+            ack_rate,
+            &sparkline(&history),
+        );
+        self.previous = Some(snapshot);
+        frame
+    }
+}
+
+fn render(
+    snapshot: &Snapshot,
+    expected: Duration,
+    send_rate: f64,
+    ack_rate: f64,
+    latency_sparkline: &str,
+) -> String {
+    let mut out = String::new();
+    let remaining = expected.saturating_sub(snapshot.elapsed);
+    let _ = writeln!(
+        out,
+        "mqtt-bench  task: {}  stage: {}  elapsed: {}  remaining: ~{}",
+        snapshot.task_name,
+        snapshot.stage,
+        format_duration(snapshot.elapsed),
+        format_duration(remaining)
+    );
+    let _ = writeln!(out, "{}", "-".repeat(78));
+    let _ = writeln!(
+        out,
+        "connections   planned {}  started {}  connecting {}  established {}  finished {}  failed {}",
+        snapshot.planned_tasks,
+        snapshot.running_tasks,
+        snapshot.ongoing_connection,
+        snapshot.established_connection,
+        snapshot.finished_tasks,
+        snapshot.failed_tasks()
+    );
+    let _ = writeln!(
+        out,
+        "publish       sent {} ({:.1}/s)  acked {} ({:.1}/s)",
+        snapshot.sent_packets, send_rate, snapshot.publish_packets, ack_rate
+    );
+    let _ = writeln!(
+        out,
+        "ack latency   p50 {:.1}ms  p90 {:.1}ms  p99 {:.1}ms  max {:.1}ms",
+        snapshot.ack_latency.p50,
+        snapshot.ack_latency.p90,
+        snapshot.ack_latency.p99,
+        snapshot.ack_latency.max
+    );
+    let _ = writeln!(out, "              {}", latency_sparkline);
+    let _ = writeln!(
+        out,
+        "errors        failed tasks {}  invalid pubacks {}  not ready {}",
+        snapshot.failed_tasks(),
+        snapshot.invalid_pubacks,
+        snapshot.timeout_pubacks
+    );
+    for (name, counts) in [
+        ("connack", &snapshot.connack_codes),
+        ("disconnects", &snapshot.disconnects),
+        ("failures", &snapshot.task_failures),
+    ] {
+        if !counts.is_empty() {
+            let _ = writeln!(out, "{:<14}{}", name, report::format_counts(counts));
+        }
+    }
+    out
+}
+
+// sparkline scales the values to the max of the values
+fn sparkline(values: &[f64]) -> String {
+    let max = values.iter().cloned().fold(0.0, f64::max);
+    values
+        .iter()
+        .map(|v| {
+            if max <= 0.0 {
+                return SPARKS[0];
+            }
+            let idx = (v / max * (SPARKS.len() - 1) as f64).round() as usize;
+            SPARKS[idx.min(SPARKS.len() - 1)]
+        })
+        .collect()
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{format_duration, sparkline, Dashboard};
+    use crate::stressing_registry::MetricRegistry;
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[0.0, 0.0]), "▁▁");
+        assert_eq!(sparkline(&[0.0, 7.0, 14.0]), "▁▅█");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(3723)), "01:02:03");
+    }
+
+    #[test]
+    fn test_frame() {
+        let reg = Arc::new(MetricRegistry::new("task-demo".to_string()));
+        reg.start_task();
+        reg.planned_tasks_add(1);
+        reg.established_connection_inc();
+        reg.sent_packets_inc();
+        reg.publish_packets_inc();
+        reg.ack_latency_observe(Duration::from_millis(5));
+
+        let mut dashboard = Dashboard::new(reg.clone(), Duration::from_secs(60));
+        let frame = dashboard.frame(reg.snapshot());
+        assert!(frame.contains("task: task-demo  stage: steady"));
+        assert!(frame.contains("established 1"));
+        assert!(frame.contains("sent 1"));
+        assert!(frame.contains("p99 5.0ms"));
+    }
+}
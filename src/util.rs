@@ -1,6 +1,25 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
 use text_template::*;
+
+use crate::error::{BenchError, BenchResult};
+
+// QUIET suppresses the console logs, e.g. when the terminal is owned by the
+// dashboard.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
 #[derive(Clone)]
 pub struct MyClient {
     pub client: reqwest::Client,
@@ -15,69 +34,121 @@ impl MyClient {
 pub fn render_template(template: &str, context: &HashMap<&str, &str>) -> String {
     let template = Template::from(template);
     let text = template.fill_in(context);
-    return text.to_string();
+    text.to_string()
+}
+
+// template_vars returns the names of the placeholders of the template
+pub fn template_vars(template: &str) -> Vec<String> {
+    Template::from(template)
+        .iter()
+        .filter_map(|piece| match piece {
+            Piece::Placeholder { name, .. } => Some(name.to_string()),
+            Piece::Text(_) => None,
+        })
+        .collect()
+}
+
+// TokenRequest is the dynamic token request of a device, its templates are
+// rendered
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenRequest {
+    pub method: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub query: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    // None for a GET request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
+    #[serde(skip)]
+    pub accepted_status: Vec<u16>,
 }
 
 pub async fn http_rpc_call(
     http_client: &Arc<MyClient>,
     http_url: &str,
-    request: &str,
+    request: &TokenRequest,
     extractor: &str,
-) -> String {
-    let url = reqwest::Url::parse(http_url).unwrap();
+) -> BenchResult<String> {
+    let url = reqwest::Url::parse(http_url)
+        .map_err(|e| BenchError::Config(format!("invalid token url {}: {}", http_url, e)))?;
+    let method = reqwest::Method::from_bytes(request.method.as_bytes())
+        .map_err(|_| BenchError::Config(format!("invalid token method {}", request.method)))?;
+    // The body carries the device password, only its length is logged
+    let body_len = request.body.as_ref().map_or(0, String::len);
 
-    let res = http_client
+    let mut builder = http_client
         .client
-        .post(url)
-        .body(request.to_owned().to_string())
-        .header("Content-type", "application/json")
-        .send()
-        .await;
-    match res {
+        .request(method, url)
+        .query(&request.query);
+    for (name, value) in &request.headers {
+        builder = builder.header(name, value);
+    }
+    if let Some(body) = &request.body {
+        builder = builder.body(body.clone());
+    }
+    if let Some(timeout) = request.timeout {
+        builder = builder.timeout(timeout);
+    }
+    match builder.send().await {
         Ok(response) => {
-            if response.status() != reqwest::StatusCode::OK {
-                println!(
-                    "request url: {:?} with body: {:?}, error: {:?}, message: {:?}",
-                    http_url,
-                    request,
-                    response.status(),
-                    response.text().await.unwrap()
+            let status = response.status();
+            if !request.accepted_status.contains(&status.as_u16()) {
+                let message = response.text().await.unwrap_or_default();
+                tracing::warn!(
+                    url = http_url,
+                    body_len,
+                    status = %status,
+                    message,
+                    "token request failed"
                 );
-                return "".to_string();
+                return Err(BenchError::TokenStatus(status.as_u16()));
             }
-            let result: String = response.text().await.unwrap();
+            let result = response
+                .text()
+                .await
+                .map_err(|e| BenchError::TokenRequest(e.to_string()))?;
             extract_token(&result, extractor)
         }
         Err(err) => {
-            println!(
-                "request url: {:?} with body: {:?}, error: {:?}",
-                http_url, request, err
+            tracing::warn!(
+                url = http_url,
+                body_len,
+                error = %err,
+                "token request failed"
             );
-            return "".to_string();
+            Err(BenchError::TokenRequest(err.to_string()))
         }
     }
 }
 
-fn extract_token(content: &str, token_extractor: &str) -> String {
-    jsonpath_rust::JsonPathFinder::from_str(content, token_extractor)
-        .unwrap()
-        .find()
+fn extract_token(content: &str, token_extractor: &str) -> BenchResult<String> {
+    let finder = jsonpath_rust::JsonPathFinder::from_str(content, token_extractor)
+        .map_err(BenchError::TokenExtract)?;
+    let found = finder.find();
+    found
         .as_array()
-        .unwrap()
-        .get(0)
-        .unwrap()
-        .as_str()
-        .unwrap()
-        .to_string()
+        .and_then(|values| values.first())
+        .and_then(|value| value.as_str())
+        .map(|token| token.to_string())
+        .ok_or_else(|| {
+            BenchError::TokenExtract(format!("{} doesn't match a string", token_extractor))
+        })
 }
 
 #[cfg(test)]
 mod util_tests {
 
+    use std::collections::HashMap;
     use std::sync::Arc;
-    use wiremock::matchers::{method, path};
+    use wiremock::matchers::{body_string, header, method, path, query_param};
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
+    use crate::config::{BasicAuth, DynamicToken};
+    use crate::error::BenchError;
     use crate::util::extract_token;
     use crate::util::MyClient;
 
@@ -113,9 +184,11 @@ mod util_tests {
 
     #[test]
     fn test_extractor() {
-        let token = extract_token(RESPONSE, TOKEN_EXTRACTOR);
+        let token = extract_token(RESPONSE, TOKEN_EXTRACTOR).unwrap();
         println!("token is {:?}", token);
-        assert!(token == "this is a real token")
+        assert!(token == "this is a real token");
+        assert!(extract_token(RESPONSE, "$.data.mqtt.port").is_err());
+        assert!(extract_token("not json", TOKEN_EXTRACTOR).is_err());
     }
 
     const PATH: &str = "/v2/things/mqtt/tokens";
@@ -127,6 +200,8 @@ mod util_tests {
 
         Mock::given(method("POST"))
             .and(path(PATH))
+            .and(header("Content-Type", "application/json"))
+            .and(body_string(REQUEST))
             .respond_with(ResponseTemplate::new(200).set_body_raw(RESPONSE, "application/json"))
             .mount(&server)
             .await;
@@ -136,8 +211,58 @@ mod util_tests {
 
         println!("make a http request to mockserver {}", url);
 
-        let result = http_rpc_call(&http_client, &url, REQUEST, TOKEN_EXTRACTOR).await;
+        let token = DynamicToken {
+            payload: REQUEST.to_string(),
+            ..DynamicToken::new()
+        };
+        let request = token.render(&HashMap::new());
+        let result = http_rpc_call(&http_client, &url, &request, TOKEN_EXTRACTOR).await;
+
+        assert_eq!(result.unwrap(), "this is a real token");
+    }
+
+    #[tokio::test]
+    async fn test_http_rpc_get() {
+        let server = MockServer::start().await;
+        let http_client = Arc::new(MyClient::new());
+
+        // "google:pw" in base64
+        Mock::given(method("GET"))
+            .and(path(PATH))
+            .and(query_param("device", "device_1"))
+            .and(header("X-Api-Key", "key-google"))
+            .and(header("Authorization", "Basic Z29vZ2xlOnB3"))
+            .respond_with(ResponseTemplate::new(201).set_body_raw(RESPONSE, "application/json"))
+            .mount(&server)
+            .await;
+
+        let url = format!("{}{}", server.uri(), PATH);
+        let mut token = DynamicToken {
+            method: "get".to_string(),
+            query: HashMap::from([("device".to_string(), "${thirdThingsId}".to_string())]),
+            headers: HashMap::from([("X-Api-Key".to_string(), "key-${tenantName}".to_string())]),
+            basic_auth: Some(BasicAuth {
+                username: "${tenantName}".to_string(),
+                password: "${password}".to_string(),
+            }),
+            accepted_status: vec![201],
+            ..DynamicToken::new()
+        };
+        let context = HashMap::from([
+            ("tenantName", "google"),
+            ("thirdThingsId", "device_1"),
+            ("password", "pw"),
+        ]);
+        let request = token.render(&context);
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.body, None);
+        let result = http_rpc_call(&http_client, &url, &request, TOKEN_EXTRACTOR).await;
+        assert_eq!(result.unwrap(), "this is a real token");
 
-        assert_eq!(result, "this is a real token");
+        // 201 isn't accepted by default
+        token.accepted_status = vec![200];
+        let request = token.render(&context);
+        let result = http_rpc_call(&http_client, &url, &request, TOKEN_EXTRACTOR).await;
+        assert_eq!(result.unwrap_err(), BenchError::TokenStatus(201));
     }
 }
@@ -0,0 +1,639 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+use base64::{engine::general_purpose, Engine as _};
+use mqtt::TopicName;
+use serde::Deserialize;
+
+use crate::config::{self, Config, GroupVersionKind, Plan, Spec, TOKEN_METHODS};
+use crate::overrides::{self, Override};
+use crate::stressing;
+use crate::util::{render_template, template_vars};
+
+// Variables every device provides to the templates
+const THINGS_VARS: [&str; 4] = ["tenantName", "infoModelName", "thirdThingsId", "password"];
+
+// Variable only the topic template provides
+const TOPIC_VARS: [&str; 1] = ["clientId"];
+
+// Issue is a problem of the config which fails the run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    // path of the field, e.g. `spec.thingsInfo[3].tenantName`, or the line and
+    // column of a syntax error
+    pub path: String,
+    pub message: String,
+}
+
+impl Issue {
+    fn new(path: impl Into<String>, message: impl Into<String>) -> Issue {
+        Issue {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+// parse unmarshals the documents of the config into a plan, the issue of a
+// syntax error is located by its line and column
+pub fn parse(contents: &str) -> Result<(String, Plan), Issue> {
+    let documents = config::specs_from_str(contents).map_err(|(idx, e)| {
+        // The spec is buffered by the `kind` tag, which loses the location
+        // of its errors, unmarshalling it alone locates them.
+        let document = || serde_yaml::Deserializer::from_str(contents).nth(idx);
+        let is_publish = document()
+            .and_then(|d| serde_yaml::Value::deserialize(d).ok())
+            .and_then(|value| value.get("kind").cloned())
+            == Some("publish".into());
+        match document().map(PublishDocument::deserialize) {
+            Some(Err(spec_error)) if is_publish => located(spec_error),
+            _ => located(e),
+        }
+    })?;
+    Plan::from_documents(documents).map_err(|e| Issue::new("config", e.to_string()))
+}
+
+fn located(e: serde_yaml::Error) -> Issue {
+    let message = e.to_string();
+    match e.location() {
+        Some(location) if message.contains(" at line ") => {
+            // The location is already part of the path
+            let message = message.rsplit_once(" at line ").unwrap().0;
+            let path = format!("line {} column {}", location.line(), location.column());
+            Issue::new(path, message)
+        }
+        _ => Issue::new("config", message),
+    }
+}
+
+#[derive(Deserialize)]
+struct PublishDocument {
+    #[allow(dead_code)]
+    spec: Config,
+}
+
+// check returns the semantic issues of the specs of the plan, the problems
+// which would otherwise only fail the tasks once they are running
+pub fn check(plan: &Plan) -> Vec<Issue> {
+    if plan.specs.is_empty() {
+        return vec![Issue::new("spec.specs", "no spec in the plan")];
+    }
+    let mut issues = vec![];
+    let mut names = HashSet::new();
+    for (idx, stressing) in plan.specs.iter().enumerate() {
+        let prefix = prefix(plan, idx);
+        let name = stressing.meta().name;
+        if !names.insert(name.clone()) {
+            issues.push(Issue::new(
+                format!("{}metaData.name", prefix),
+                format!(
+                    "spec name {} is duplicated, it's the task name of the metrics",
+                    name
+                ),
+            ));
+        }
+        let spec_issues = match &stressing.spec {
+            Spec::Publish(config) => check_publish(config),
+            Spec::Test(_) => vec![Issue::new("kind", "kind test can't be run, use publish")],
+            Spec::Plan(_) => vec![Issue::new("kind", "a plan can't be nested")],
+        };
+        issues.extend(
+            spec_issues
+                .into_iter()
+                .map(|issue| Issue::new(prefix.clone() + &issue.path, issue.message)),
+        );
+    }
+    issues
+}
+
+// load opens the device files of the specs relative to the directory of the
+// config, the issue of a file which can't be read has the row of the error
+pub fn load(plan: &mut Plan, dir: &Path) -> Vec<Issue> {
+    let mut issues = vec![];
+    for idx in 0..plan.specs.len() {
+        let prefix = prefix(plan, idx);
+        let Spec::Publish(config) = &mut plan.specs[idx].spec else {
+            continue;
+        };
+        for (entry_idx, e) in config.things_info.load(dir) {
+            issues.push(Issue::new(
+                format!("{}spec.thingsInfo[{}].file", prefix, entry_idx),
+                e.to_string(),
+            ));
+        }
+    }
+    issues
+}
+
+// prefix is the prefix of the paths of a spec, the paths of a plan are
+// prefixed by the index of the spec
+fn prefix(plan: &Plan, idx: usize) -> String {
+    if plan.specs.len() > 1 {
+        format!("specs[{}].", idx)
+    } else {
+        String::new()
+    }
+}
+
+fn check_publish(config: &Config) -> Vec<Issue> {
+    let mut issues = vec![];
+    if config.broker_addr.is_empty() {
+        issues.push(Issue::new(
+            "spec.brokerAddr",
+            "at least a broker is required",
+        ));
+    }
+    if config.think_time <= 0 {
+        issues.push(Issue::new("spec.thinkTime", "must be positive"));
+    }
+    if config.duration < 0 {
+        issues.push(Issue::new("spec.duration", "can't be negative"));
+    }
+    if config.things_info.is_empty() {
+        issues.push(Issue::new("spec.thingsInfo", "no device to connect"));
+    }
+
+    let mut tenants: Vec<&String> = config.things_payloads.keys().collect();
+    tenants.sort();
+    for tenant in tenants {
+        if !config.is_payload_base64 {
+            break;
+        }
+        if let Err(e) = general_purpose::STANDARD.decode(&config.things_payloads[tenant]) {
+            issues.push(Issue::new(
+                format!("spec.thingsPayloads.{}", tenant),
+                format!("isn't base64: {}", e),
+            ));
+        }
+    }
+
+    let topic_vars = template_vars(&config.topic_template);
+    let token_vars: Vec<(String, Vec<String>)> = if config.dynamic_token.url.is_empty() {
+        vec![]
+    } else {
+        config
+            .dynamic_token
+            .templates()
+            .into_iter()
+            .map(|(field, template)| (field, template_vars(template)))
+            .collect()
+    };
+    let mut reported = HashSet::new();
+    let mut invalid_topics = 0;
+    for (idx, entry) in config.things_info.entries().iter().enumerate() {
+        if entry.file.is_some() {
+            if entry.range.is_some() {
+                issues.push(Issue::new(
+                    format!("spec.thingsInfo[{}].range", idx),
+                    "the devices of a file can't be generated",
+                ));
+            }
+            continue;
+        }
+        let required = [
+            ("tenantName", &entry.tenant_name),
+            ("infoModelName", &entry.info_model_name),
+            ("thirdThingsId", &entry.third_things_id),
+        ];
+        for (field, value) in required {
+            if value.is_empty() {
+                issues.push(Issue::new(
+                    format!("spec.thingsInfo[{}].{}", idx, field),
+                    "is required",
+                ));
+            }
+        }
+        let Some(range) = entry.range else {
+            continue;
+        };
+        if range.count == 0 {
+            issues.push(Issue::new(
+                format!("spec.thingsInfo[{}].range.count", idx),
+                "must be positive",
+            ));
+        }
+        if !entry.third_things_id.contains("${index") {
+            issues.push(Issue::new(
+                format!("spec.thingsInfo[{}].thirdThingsId", idx),
+                "must contain ${index}, the generated devices would share an id",
+            ));
+        }
+    }
+    // The devices of a file are checked when it's scanned. The devices of a
+    // generator only differ by their index, its first and last devices stand
+    // for the others, so a large range isn't expanded.
+    let generated = config.things_info.entries().iter().enumerate();
+    let sampled = generated
+        .filter(|(_, entry)| entry.file.is_none())
+        .flat_map(|(idx, _)| {
+            let indexes = config.things_info.indexes(idx);
+            let last = indexes
+                .end
+                .checked_sub(1)
+                .filter(|last| *last > indexes.start);
+            indexes.clone().take(1).chain(last)
+        });
+    for device_idx in sampled {
+        // The issues refer to the entry, a generator has many devices
+        let idx = config.things_info.location(device_idx).0;
+        let path = |field: &str| format!("spec.thingsInfo[{}]{}", idx, field);
+        let things = match config.things_info.device(device_idx) {
+            Ok(things) => things,
+            Err(e) => {
+                issues.push(Issue::new(path(""), e.to_string()));
+                continue;
+            }
+        };
+        let fields = [
+            &things.tenant_name,
+            &things.info_model_name,
+            &things.third_things_id,
+            &things.password,
+        ];
+        if fields
+            .into_iter()
+            .chain(things.context.values())
+            .any(|field| field.contains("${index"))
+            && reported.insert(("index", idx.to_string()))
+        {
+            issues.push(Issue::new(
+                path(""),
+                "${index} isn't expanded, the entry has no range or the pattern is invalid",
+            ));
+        }
+        if !config.things_payloads.contains_key(&things.tenant_name)
+            && reported.insert(("tenant", things.tenant_name.clone()))
+        {
+            issues.push(Issue::new(
+                path(".tenantName"),
+                format!(
+                    "tenant {} has no payload in thingsPayloads",
+                    things.tenant_name
+                ),
+            ));
+        }
+
+        let known = |var: &String, extra: &[&str]| {
+            THINGS_VARS.contains(&var.as_str())
+                || extra.contains(&var.as_str())
+                || things.context.contains_key(var)
+        };
+        for var in &topic_vars {
+            if !known(var, &TOPIC_VARS) && reported.insert(("topic", var.clone())) {
+                issues.push(Issue::new(
+                    "spec.topicTemplate",
+                    format!("unknown variable ${{{}}}, e.g. of thingsInfo[{}]", var, idx),
+                ));
+            }
+        }
+        for (field, vars) in &token_vars {
+            for var in vars {
+                if !known(var, &[]) && reported.insert(("token", format!("{}.{}", field, var))) {
+                    issues.push(Issue::new(
+                        format!("spec.dynamicToken.{}", field),
+                        format!("unknown variable ${{{}}}, e.g. of thingsInfo[{}]", var, idx),
+                    ));
+                }
+            }
+        }
+
+        let client_id = config.get_client_id(&things);
+        let topic = render_template(&config.topic_template, &things.to_context(&client_id));
+        if let Err(e) = TopicName::new(topic.clone()) {
+            invalid_topics += 1;
+            if invalid_topics == 1 {
+                issues.push(Issue::new(
+                    path(""),
+                    format!("invalid topic {:?}: {}", topic, e),
+                ));
+            }
+        }
+    }
+    if invalid_topics > 1 {
+        issues.push(Issue::new(
+            "spec.topicTemplate",
+            format!("{} devices have an invalid topic", invalid_topics),
+        ));
+    }
+
+    if !config.dynamic_token.url.is_empty() {
+        for (idx, url) in config.dynamic_token.urls().iter().enumerate() {
+            if let Err(e) = reqwest::Url::parse(url) {
+                let path = if config.dynamic_token.servers.is_empty() {
+                    "spec.dynamicToken.url".to_string()
+                } else {
+                    format!("spec.dynamicToken.servers[{}]", idx)
+                };
+                issues.push(Issue::new(path, format!("invalid url {}: {}", url, e)));
+            }
+        }
+        let token = &config.dynamic_token;
+        if !TOKEN_METHODS.contains(&token.method.to_ascii_uppercase().as_str()) {
+            issues.push(Issue::new(
+                "spec.dynamicToken.method",
+                format!(
+                    "method {} isn't supported, it should be one of {}",
+                    token.method,
+                    TOKEN_METHODS.join(", ")
+                ),
+            ));
+        }
+        let mut headers: Vec<&String> = token.headers.keys().collect();
+        headers.sort();
+        for name in headers {
+            if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                issues.push(Issue::new(
+                    format!("spec.dynamicToken.headers.{}", name),
+                    "isn't a valid header name",
+                ));
+            }
+        }
+        if token.accepted_status.is_empty() {
+            issues.push(Issue::new(
+                "spec.dynamicToken.acceptedStatus",
+                "no status is accepted",
+            ));
+        }
+        for (idx, status) in token.accepted_status.iter().enumerate() {
+            if !(100..=599).contains(status) {
+                issues.push(Issue::new(
+                    format!("spec.dynamicToken.acceptedStatus[{}]", idx),
+                    format!("{} isn't an HTTP status", status),
+                ));
+            }
+        }
+    }
+
+    if let Err(e) = stressing::tls_connector(&config.tls) {
+        issues.push(Issue::new("spec.tls", e.to_string()));
+    }
+    issues
+}
+
+pub fn command() -> clap::Command {
+    clap::Command::new("validate")
+        .about("Check the config file without running it")
+        .arg(
+            clap::arg!(--"file" <PATH>)
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .short(Some('f'))
+                .required(true)
+                .help("Config file for stress test"),
+        )
+        .args(overrides::args())
+}
+
+// Resolved is the config as it's parsed
+pub struct Resolved {
+    pub contents: String,
+    // set when the environment variables, the includes, a migration or the
+    // overrides rewrote the config, its lines and columns don't match the file
+    pub rewritten: bool,
+}
+
+impl Resolved {
+    // parse unmarshals the config like `parse`, the issue of a rewritten
+    // config has no line and column
+    pub fn parse(&self) -> Result<(String, Plan), Issue> {
+        parse(&self.contents).map_err(|issue| {
+            if self.rewritten && issue.path.starts_with("line ") {
+                Issue::new("config", issue.message)
+            } else {
+                issue
+            }
+        })
+    }
+}
+
+// read reads the config, resolves its environment variables and includes and
+// applies the overrides, the error is the exit code of the subcommand. The
+// errors and the notes go to stderr, stdout may be the output of the
+// subcommand, e.g. explain.
+pub fn read(path: &Path, overrides: &[Override]) -> Result<Resolved, i32> {
+    let original = std::fs::read_to_string(path).map_err(|e| {
+        eprintln!("read config {} failed: {}", path.display(), e);
+        2
+    })?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let (contents, notes) = config::resolve(&original, dir, overrides).map_err(|e| {
+        eprintln!(
+            "{}: {}",
+            path.display(),
+            Issue::new("config", e.to_string())
+        );
+        1
+    })?;
+    for note in &notes {
+        eprintln!("{}: {}", path.display(), note);
+    }
+    if !notes.is_empty() {
+        eprintln!(
+            "{}: the config is upgraded in memory, upgrade the file by `mqtt-bench migrate`",
+            path.display()
+        );
+    }
+    Ok(Resolved {
+        rewritten: contents != original,
+        contents,
+    })
+}
+
+// execute prints the issues of the config, the exit code is non-zero when
+// the config is invalid
+pub fn execute(matches: &clap::ArgMatches) -> i32 {
+    let path = matches.get_one::<std::path::PathBuf>("file").unwrap();
+    let config = match read(path, &overrides::from_matches(matches)) {
+        Ok(config) => config,
+        Err(code) => return code,
+    };
+    let issues = match config.parse() {
+        Ok((_, mut plan)) => {
+            let mut issues = load(&mut plan, path.parent().unwrap_or(Path::new(".")));
+            issues.extend(check(&plan));
+            issues
+        }
+        Err(issue) => vec![issue],
+    };
+    if issues.is_empty() {
+        println!("{} is valid", path.display());
+        return 0;
+    }
+    for issue in &issues {
+        println!("{}: {}", path.display(), issue);
+    }
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, parse, read};
+
+    const VALID: &str = r#"group: github.com/zhao-kun/mqtt-bench
+version: v1.0.1
+kind: publish
+metaData:
+  name: task-demo
+spec:
+  brokerAddr: ["127.0.0.1:1883"]
+  dynamicToken:
+    servers:
+    - http://192.168.1.1
+    url: /v2/things/mqtt/tokens
+    payload: '{"username": "${tenantName}", "site": "${site}"}'
+    tokenExtractor: "$.data.token"
+  topicTemplate: /d2s/${tenantName}/${infoModelName}/${clientId}
+  isPayloadBase64: false
+  thingsPayloads:
+    "google": "hello world"
+  thingsInfo:
+  - tenantName: "google"
+    infoModelName: "demo_v1"
+    thirdThingsId: thirdThingsID
+    password: "things_password"
+    context:
+      site: "north"
+"#;
+
+    #[test]
+    fn test_valid_config() {
+        let (name, plan) = parse(VALID).unwrap();
+        assert_eq!(name, "task-demo");
+        assert_eq!(check(&plan), vec![]);
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let issue = parse(&VALID.replace("  thingsInfo:", "  thingsInfo: [")).unwrap_err();
+        assert_eq!(issue.path, "line 19 column 3");
+        assert!(!issue.message.contains(" at line "), "{}", issue);
+    }
+
+    #[test]
+    fn test_rewritten_config() {
+        let path =
+            std::env::temp_dir().join(format!("mqtt-bench-validate-{}.yaml", std::process::id()));
+        let invalid = VALID.replace("  thingsInfo:", "  thinkTime: xx\n  thingsInfo:");
+
+        std::fs::write(&path, &invalid).unwrap();
+        let config = read(&path, &[]).unwrap();
+        assert!(!config.rewritten);
+        assert_eq!(config.parse().unwrap_err().path, "line 18 column 14");
+
+        // The lines of the rewritten config don't match the file
+        let substituted =
+            invalid.replace("127.0.0.1:1883", "${env:MQTT_BENCH_BROKER:-127.0.0.1:1883}");
+        std::fs::write(&path, substituted).unwrap();
+        let config = read(&path, &[]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(config.rewritten);
+        let issue = config.parse().unwrap_err();
+        assert_eq!(issue.path, "config");
+        assert!(issue.message.contains("thinkTime"), "{}", issue);
+    }
+
+    #[test]
+    fn test_semantic_issues() {
+        let contents = VALID
+            .replace("http://192.168.1.1", "192.168.1.1")
+            .replace("${clientId}", "${thingsId}/#")
+            .replace("isPayloadBase64: false", "isPayloadBase64: true")
+            .replace("\"google\": ", "\"amazon\": ");
+        let issues: Vec<String> = check(&parse(&contents).unwrap().1)
+            .iter()
+            .map(|issue| issue.to_string())
+            .collect();
+        assert_eq!(issues.len(), 5, "{:?}", issues);
+        assert!(issues[0].starts_with("spec.thingsPayloads.amazon: isn't base64"));
+        assert_eq!(
+            issues[1],
+            "spec.thingsInfo[0].tenantName: tenant google has no payload in thingsPayloads"
+        );
+        assert_eq!(
+            issues[2],
+            "spec.topicTemplate: unknown variable ${thingsId}, e.g. of thingsInfo[0]"
+        );
+        assert!(issues[3].starts_with("spec.thingsInfo[0]: invalid topic"));
+        assert!(issues[4].starts_with("spec.dynamicToken.servers[0]: invalid url"));
+    }
+
+    #[test]
+    fn test_generated_devices() {
+        let generator = VALID.replace("thirdThingsID", "device_${index:06}");
+        let contents = format!("{}    range: {{start: 1, count: 3}}\n", generator);
+        assert_eq!(check(&parse(&contents).unwrap().1), vec![]);
+
+        let contents = format!(
+            "{}    range: {{start: 1, count: 3}}\n  - tenantName: \"google\"\n    infoModelName: m\n    thirdThingsId: d_${{index:x}}\n    password: p\n    range: {{count: 2}}\n  - tenantName: \"google\"\n    infoModelName: m\n    thirdThingsId: d\n    password: p\n    range: {{count: 0}}\n",
+            generator
+        );
+        let issues: Vec<String> = check(&parse(&contents).unwrap().1)
+            .iter()
+            .map(|issue| issue.to_string())
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                "spec.thingsInfo[2].range.count: must be positive",
+                "spec.thingsInfo[2].thirdThingsId: must contain ${index}, the generated devices would share an id",
+                "spec.thingsInfo[1]: ${index} isn't expanded, the entry has no range or the pattern is invalid",
+                "spec.dynamicToken.payload: unknown variable ${site}, e.g. of thingsInfo[1]",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_token_request() {
+        let contents = VALID.replace(
+            "    tokenExtractor: \"$.data.token\"\n",
+            "    tokenExtractor: \"$.data.token\"\n    method: DELETE\n    query: {device: \"${thirdThingsId}\", zone: \"${zone}\"}\n    headers: {\"X Api Key\": \"${apiKey}\"}\n    acceptedStatus: [200, 1000]\n",
+        );
+        let issues: Vec<String> = check(&parse(&contents).unwrap().1)
+            .iter()
+            .map(|issue| issue.to_string())
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                "spec.dynamicToken.query.zone: unknown variable ${zone}, e.g. of thingsInfo[0]",
+                "spec.dynamicToken.headers.X Api Key: unknown variable ${apiKey}, e.g. of thingsInfo[0]",
+                "spec.dynamicToken.method: method DELETE isn't supported, it should be one of GET, POST, PUT",
+                "spec.dynamicToken.headers.X Api Key: isn't a valid header name",
+                "spec.dynamicToken.acceptedStatus[1]: 1000 isn't an HTTP status",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multiple_documents() {
+        let second = VALID
+            .replace("task-demo", "storm")
+            .replace("  isPayloadBase64", "  thinkTime: -1\n  isPayloadBase64");
+        let contents = format!("{}---\n{}", VALID, second);
+        let issues: Vec<String> = check(&parse(&contents).unwrap().1)
+            .iter()
+            .map(|issue| issue.to_string())
+            .collect();
+        assert_eq!(issues, vec!["specs[1].spec.thinkTime: must be positive"]);
+
+        let contents = format!("{}---\n{}", VALID, VALID);
+        let issues = check(&parse(&contents).unwrap().1);
+        assert_eq!(issues[0].path, "specs[1].metaData.name");
+
+        // The syntax errors of the second document are located in the file
+        let second = VALID.replace("  isPayloadBase64", "  thinkTime: x\n  isPayloadBase64");
+        let issue = parse(&format!("{}---\n{}", VALID, second)).unwrap_err();
+        let line = VALID.lines().count() + 1 + 15;
+        assert_eq!(issue.path, format!("line {} column 14", line));
+        assert_eq!(
+            issue.message,
+            "spec.thinkTime: invalid type: string \"x\", expected i32"
+        );
+    }
+}